@@ -0,0 +1,8 @@
+alter table audios add column language char(2);
+
+update audios
+set language = users.language
+from users
+where audios.user_id = users.id;
+
+alter table audios alter column language set not null;
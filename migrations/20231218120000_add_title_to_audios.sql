@@ -0,0 +1 @@
+alter table audios add column title varchar(255);
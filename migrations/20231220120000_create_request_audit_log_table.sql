@@ -0,0 +1,14 @@
+create table request_audit_log (
+    id serial primary key,
+    request_id varchar(255),
+    user_id int,
+    method varchar(10) not null,
+    path text not null,
+    status_code int not null,
+    duration_ms bigint not null,
+    request_body_size bigint,
+    response_body_size bigint,
+    created_at timestamptz not null default now(),
+
+    foreign key (user_id) references users (id) on delete set null
+)
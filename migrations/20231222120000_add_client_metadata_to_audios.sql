@@ -0,0 +1,2 @@
+alter table audios add column user_agent text;
+alter table audios add column client_ip varchar(45);
@@ -0,0 +1 @@
+alter table audios add column content_type varchar(255) not null default 'audio/webm';
@@ -0,0 +1,14 @@
+-- Accounts whose emails only differ by case or surrounding whitespace would
+-- collide once normalized. The oldest one keeps the email, the others are
+-- renamed to an address under the reserved .invalid domain that keeps their id,
+-- so they can't log in by email until an admin merges or fixes them by hand.
+update users set email = left(lower(trim(email)), 200) || '.duplicate-' || id || '.invalid'
+where id in (
+    select id from (
+        select id, row_number() over (partition by lower(trim(email)) order by id) as position
+        from users
+    ) as normalized
+    where position > 1
+);
+
+update users set email = lower(trim(email)) where email <> lower(trim(email));
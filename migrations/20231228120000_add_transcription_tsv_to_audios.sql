@@ -0,0 +1,5 @@
+alter table audios
+        add column transcription_tsv tsvector
+        generated always as (to_tsvector('simple', coalesce(transcription, ''))) stored;
+
+create index audios_transcription_fts_idx on audios using gin (transcription_tsv);
@@ -0,0 +1,14 @@
+create table transcription_versions (
+    id serial primary key,
+    audio_id int not null,
+    transcription text not null,
+    source varchar(32) not null,
+    created_at timestamptz not null default now(),
+
+    foreign key (audio_id) references audios (id) on delete cascade
+);
+
+create index transcription_versions_audio_id_idx on transcription_versions (audio_id);
+
+insert into transcription_versions (audio_id, transcription, source, created_at)
+select id, transcription, 'unknown', created_at from audios where transcription is not null;
@@ -0,0 +1,5 @@
+alter table audios alter column user_id drop not null;
+alter table audios add column claim_token varchar(255);
+alter table audios add column claim_expires_at timestamptz;
+alter table audios add constraint audios_user_or_claim_check
+    check (user_id is not null or claim_token is not null);
@@ -0,0 +1,2 @@
+alter table audios add column transcription_status varchar(16) not null default 'pending';
+update audios set transcription_status = 'completed' where transcription is not null;
@@ -0,0 +1,2 @@
+alter table audios add column transcription_provider text;
+update audios set transcription_provider = 'unknown' where transcription is not null;
@@ -0,0 +1,12 @@
+create table auth_audit_log (
+    id serial primary key,
+    event_type varchar(32) not null,
+    user_id int,
+    ip_address varchar(45) not null,
+    user_agent text,
+    created_at timestamptz not null default now(),
+
+    foreign key (user_id) references users (id) on delete set null
+);
+
+create index auth_audit_log_user_id_idx on auth_audit_log (user_id);
@@ -0,0 +1 @@
+alter table audios add column needs_review boolean not null default false;
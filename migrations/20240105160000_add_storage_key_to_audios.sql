@@ -0,0 +1 @@
+alter table audios add column storage_key varchar(64) unique;
@@ -0,0 +1,12 @@
+create table transcription_chunks (
+    audio_id int not null,
+    chunk_index int not null,
+    chunk_secs int not null,
+    language char(2) not null,
+    transcription text not null,
+    confidence real,
+    created_at timestamptz not null default now(),
+
+    primary key (audio_id, chunk_index),
+    foreign key (audio_id) references audios (id) on delete cascade
+);
@@ -0,0 +1,10 @@
+create table email_log (
+    id serial primary key,
+    recipient text not null,
+    subject text not null,
+    status varchar(16) not null,
+    error text,
+    created_at timestamptz not null default now()
+);
+
+create index email_log_recipient_idx on email_log (recipient);
@@ -0,0 +1 @@
+alter table audios add column detected_language varchar(10);
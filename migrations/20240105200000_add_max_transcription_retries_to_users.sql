@@ -0,0 +1 @@
+alter table users add column max_transcription_retries smallint not null default 3;
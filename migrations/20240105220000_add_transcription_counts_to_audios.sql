@@ -0,0 +1,10 @@
+alter table audios add column transcription_word_count int;
+alter table audios add column transcription_char_count int;
+
+update audios
+set transcription_word_count = case
+        when btrim(transcription) = '' then 0
+        else array_length(regexp_split_to_array(btrim(transcription), '\s+'), 1)
+    end,
+    transcription_char_count = char_length(transcription)
+where transcription is not null;
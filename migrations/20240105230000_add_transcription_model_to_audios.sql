@@ -0,0 +1 @@
+alter table audios add column transcription_model varchar(64);
@@ -0,0 +1,11 @@
+create table audio_events (
+    id serial primary key,
+    audio_id int not null,
+    event_type varchar(32) not null,
+    error text,
+    created_at timestamptz not null default now(),
+
+    foreign key (audio_id) references audios (id) on delete cascade
+);
+
+create index audio_events_audio_id_idx on audio_events (audio_id);
@@ -0,0 +1,5 @@
+alter table audios add column updated_at timestamptz not null default now();
+
+update audios set updated_at = created_at;
+
+create index audios_user_id_updated_at_idx on audios (user_id, updated_at);
@@ -0,0 +1,10 @@
+create table tag_rules (
+    id serial primary key,
+    user_id int not null,
+    keyword varchar(64) not null,
+    tag_name varchar(25) not null,
+    created_at timestamptz not null default now(),
+
+    foreign key (user_id) references users (id) on delete cascade,
+    unique (user_id, keyword, tag_name)
+);
@@ -0,0 +1,2 @@
+alter table transcription_versions add column provider text;
+update transcription_versions set provider = source where source not in ('human', 'revert', 'unknown');
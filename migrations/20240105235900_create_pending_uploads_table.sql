@@ -0,0 +1,9 @@
+-- Presigned uploads that were not confirmed yet, their files are stored before
+-- they have an audio
+create table pending_uploads (
+    storage_key text primary key,
+    user_id int not null,
+    expires_at timestamptz not null,
+
+    foreign key (user_id) references users (id) on delete cascade
+);
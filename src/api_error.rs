@@ -1,9 +1,14 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
-    Json,
+    BoxError, Json,
 };
+use http_body::LengthLimitError;
+use serde::Serialize;
 use serde_json::json;
+use sqlx::error::ErrorKind;
+
+use crate::audio_storage::StorageError;
 use zxcvbn::feedback::Feedback;
 
 pub type Result<T> = std::result::Result<T, ApiError>;
@@ -13,10 +18,31 @@ pub enum ApiError {
     InternalServerError,
     NotFound,
     Unauthorized,
+    /// Authenticated, but not allowed to do this. Handlers of resources owned
+    /// by other users respond with NotFound instead, to not reveal they exist
+    Forbidden,
     BadRequest,
+    Conflict(&'static str),
+    PayloadTooLarge,
+    LimitExceeded(&'static str),
+    TooManyRequests,
+    StorageUnavailable,
+    TranscriptionUnavailable,
+    NotImplemented,
+    /// Writes are paused while maintenance mode is on
+    MaintenanceMode,
+    UnsupportedMediaType(Vec<String>),
+    ValidationError(Vec<FieldError>),
     WeakPassword(Feedback),
 }
 
+/// A problem with one of the fields of a request
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         let (status_code, msg) = match self {
@@ -26,7 +52,37 @@ impl IntoResponse for ApiError {
             }
             ApiError::NotFound => (StatusCode::NOT_FOUND, "Not found"),
             ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+            ApiError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
             ApiError::BadRequest => (StatusCode::BAD_REQUEST, "Bad request"),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::LimitExceeded(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg),
+            ApiError::PayloadTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "Payload too large"),
+            ApiError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, "Too many requests"),
+            ApiError::TranscriptionUnavailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, "Transcription unavailable")
+            }
+            ApiError::StorageUnavailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, "Storage unavailable")
+            }
+            ApiError::NotImplemented => (StatusCode::NOT_IMPLEMENTED, "Not implemented"),
+            ApiError::MaintenanceMode => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Under maintenance, only reads are allowed",
+            ),
+            ApiError::UnsupportedMediaType(supported) => {
+                let body = Json(json!({
+                    "error": "Unsupported media type",
+                    "supported": supported
+                }));
+                return (StatusCode::UNSUPPORTED_MEDIA_TYPE, body).into_response();
+            }
+            ApiError::ValidationError(errors) => {
+                let body = Json(json!({
+                    "error": "Validation error",
+                    "errors": errors
+                }));
+                return (StatusCode::BAD_REQUEST, body).into_response();
+            }
             ApiError::WeakPassword(feedback) => {
                 let suggestions = feedback
                     .suggestions()
@@ -49,6 +105,16 @@ impl IntoResponse for ApiError {
 
 impl From<sqlx::Error> for ApiError {
     fn from(error: sqlx::Error) -> Self {
+        // Rely on the database constraints instead of checking beforehand, so
+        // concurrent requests can't race each other.
+        if let Some(db_error) = error.as_database_error() {
+            if db_error.kind() == ErrorKind::UniqueViolation {
+                return match db_error.constraint() {
+                    Some("users_email_key") => ApiError::Conflict("Email already registered"),
+                    _ => ApiError::Conflict("Conflict"),
+                };
+            }
+        }
         tracing::error!(%error, "sqlx error");
         ApiError::InternalServerError
     }
@@ -60,3 +126,46 @@ impl From<anyhow::Error> for ApiError {
         ApiError::InternalServerError
     }
 }
+
+impl From<BoxError> for ApiError {
+    fn from(error: BoxError) -> Self {
+        if is_length_limit_error(&*error) {
+            return ApiError::PayloadTooLarge;
+        }
+        tracing::error!(?error, "boxed error");
+        ApiError::InternalServerError
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::NotFound => ApiError::NotFound,
+            // A body over `RequestBodyLimitLayer`'s limit fails while being stored
+            StorageError::Other(ref err) if err.chain().any(is_length_limit_error) => {
+                ApiError::PayloadTooLarge
+            }
+            StorageError::Unsupported => ApiError::NotImplemented,
+            StorageError::Full(_) | StorageError::Unavailable(_) => {
+                tracing::error!(?error, "storage error");
+                ApiError::StorageUnavailable
+            }
+            StorageError::Other(_) => {
+                tracing::error!(?error, "storage error");
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+/// Whether `error` or one of its sources is the body limit being exceeded
+pub(crate) fn is_length_limit_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(current) = error {
+        if current.is::<LengthLimitError>() {
+            return true;
+        }
+        error = current.source();
+    }
+    false
+}
@@ -1,14 +1,19 @@
 use anyhow::Context;
-use axum::{async_trait, extract::BodyStream, BoxError};
+use axum::{async_trait, BoxError};
 use azure_core::Pageable;
-use azure_storage::StorageCredentials;
+use azure_storage::{
+    shared_access_signature::service_sas::BlobSasPermissions, CloudLocation, ConnectionString,
+    StorageCredentials,
+};
 use azure_storage_blobs::{
     blob::{operations::GetBlobResponse, BlobBlockType, BlockList},
     prelude::{BlobClient, ClientBuilder},
 };
+use chrono::{DateTime, Utc};
 use futures::{Stream, StreamExt, TryStreamExt};
 use std::{
     io,
+    num::NonZeroU32,
     path::{Path, PathBuf},
     pin::Pin,
 };
@@ -18,89 +23,450 @@ use tokio_util::{
     io::{ReaderStream, StreamReader},
 };
 
-use crate::routes::audios::AUDIO_FILE_MIMETYPE;
+use crate::database::DbAudio;
 
-pub const AUDIO_FILE_EXTENSION: &str = ".webm";
+/// Written and deleted by the health check, not a valid audio key
+const HEALTH_CHECK_FILE: &str = ".health_check";
 const UPLOADS_DIRECTORY: &str = "uploads";
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum StorageBackend {
+    Local,
+    Azure,
+}
+
+/// How the keys of new audios are chosen, existing audios keep their key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKeyScheme {
+    /// `{audio_id}{extension}`
+    Id,
+    /// `{uuid}{extension}`, saved in the audio row, so keys can't be enumerated
+    Random,
+}
+
+/// What a presigned url allows doing with the file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresignAction {
+    Upload,
+    Download,
+}
+
+/// Content types audios can be stored as, with the file extension used for each
+const AUDIO_FORMATS: &[(&str, &str)] = &[
+    ("audio/webm", ".webm"),
+    ("audio/ogg", ".ogg"),
+    ("audio/mpeg", ".mp3"),
+    ("audio/mp4", ".m4a"),
+    ("audio/wav", ".wav"),
+    ("audio/flac", ".flac"),
+];
+
+/// Storages are keyed by `{audio_id}{extension}` or `{uuid}{extension}`, see
+/// [`audio_key`]
 #[async_trait]
 pub trait AudioStorage {
-    async fn get(&self, audio_id: i32) -> anyhow::Result<AudioStream>;
+    async fn get(&self, key: &str) -> StorageResult<AudioStream>;
+
+    async fn store(&self, key: &str, stream: AudioStream) -> StorageResult<()>;
+
+    async fn delete(&self, key: &str) -> StorageResult<()>;
 
-    async fn store(&self, audio_id: i32, stream: BodyStream) -> anyhow::Result<()>;
+    /// Keys of every stored audio
+    async fn list(&self) -> StorageResult<Vec<String>>;
 
-    async fn delete(&self, audio_id: i32) -> anyhow::Result<()>;
+    async fn exists(&self, key: &str) -> StorageResult<bool>;
+
+    /// Bytes stored for `key`
+    async fn size(&self, key: &str) -> StorageResult<u64>;
+
+    /// A url that clients can use to access `key` directly, without going
+    /// through this server, until `expires_at`
+    async fn presign(
+        &self,
+        _key: &str,
+        _action: PresignAction,
+        _expires_at: DateTime<Utc>,
+    ) -> StorageResult<String> {
+        Err(StorageError::Unsupported)
+    }
+
+    /// Check that the storage is reachable and writable
+    async fn health_check(&self) -> StorageResult<()> {
+        Ok(())
+    }
+}
+
+/// Get the file extension used for audios with `content_type`, its
+/// parameters are ignored
+pub fn file_extension(content_type: &str) -> Option<&'static str> {
+    let media_type = media_type(content_type)?;
+    AUDIO_FORMATS
+        .iter()
+        .find(|(format, _)| *format == media_type)
+        .map(|(_, extension)| *extension)
 }
 
-pub struct LocalAudioStorage;
+/// The lowercase `type/subtype` of a content type, without parameters like
+/// the codecs browsers add to recordings. None if it isn't a valid media type
+pub fn media_type(content_type: &str) -> Option<String> {
+    let mime: mime::Mime = content_type.trim().parse().ok()?;
+    Some(mime.essence_str().to_ascii_lowercase())
+}
 
+/// Bytes needed at the start of a file by [`sniff_content_type`]
+pub const SNIFF_LENGTH: usize = 12;
+
+/// Guess the content type of an audio from the magic bytes its container
+/// starts with, None if it isn't one of the supported formats
+pub fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        Some("audio/webm")
+    } else if bytes.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if bytes.starts_with(b"ID3")
+        || (bytes.len() >= 2 && bytes[0] == 0xff && bytes[1] & 0xe0 == 0xe0)
+    {
+        Some("audio/mpeg")
+    } else if bytes.get(4..8) == Some(b"ftyp") {
+        Some("audio/mp4")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+        Some("audio/wav")
+    } else if bytes.starts_with(b"fLaC") {
+        Some("audio/flac")
+    } else {
+        None
+    }
+}
+
+/// Get the content type of the audio stored at `key`
+fn content_type_of(key: &str) -> Option<&'static str> {
+    AUDIO_FORMATS
+        .iter()
+        .find(|(_, extension)| key.ends_with(extension))
+        .map(|(format, _)| *format)
+}
+
+/// Get the key `audio` is stored with
+pub fn audio_key(audio: &DbAudio) -> String {
+    match &audio.storage_key {
+        Some(key) => key.clone(),
+        None => storage_key(audio.id, &audio.content_type),
+    }
+}
+
+/// A new random key for an audio with `content_type`, to be saved in its row
+pub fn random_storage_key(content_type: &str) -> String {
+    let extension = file_extension(content_type).unwrap_or(".webm");
+    format!("{}{extension}", uuid::Uuid::new_v4())
+}
+
+pub fn storage_key(audio_id: i32, content_type: &str) -> String {
+    // content types are validated on upload, fall back for rows that predate that
+    let extension = file_extension(content_type).unwrap_or(".webm");
+    format!("{audio_id}{extension}")
+}
+
+/// Stores audios in `uploads/`. Sharded storages put each audio in a
+/// subdirectory named after a hash of its key, files from before sharding was
+/// enabled are still found in `uploads/` itself.
+pub struct LocalAudioStorage {
+    sharded: bool,
+}
+
+#[allow(dead_code)]
 pub struct MockAudioStorage;
 
 pub struct AzureAudioStorage {
     storage_credentials: StorageCredentials,
-    account: String,
+    location: CloudLocation,
     container: String,
+    block_size: usize,
 }
 
 impl LocalAudioStorage {
-    pub async fn new() -> anyhow::Result<LocalAudioStorage> {
+    pub async fn new(sharded: bool) -> anyhow::Result<LocalAudioStorage> {
         if !Path::new(UPLOADS_DIRECTORY).exists() {
             tokio::fs::create_dir(UPLOADS_DIRECTORY)
                 .await
                 .context("failed to create the uploads directory")?;
         }
-        Ok(LocalAudioStorage)
+        Ok(LocalAudioStorage { sharded })
     }
 }
 
 #[async_trait]
 impl AudioStorage for LocalAudioStorage {
-    async fn get(&self, audio_id: i32) -> anyhow::Result<AudioStream> {
-        let file = tokio::fs::File::open(self.get_path(audio_id)).await?;
+    async fn get(&self, key: &str) -> StorageResult<AudioStream> {
+        let file = tokio::fs::File::open(self.find_path(key).await?).await?;
         Ok(AudioStream::from_file(file))
     }
 
-    async fn store(&self, audio_id: i32, stream: BodyStream) -> anyhow::Result<()> {
-        let path = self.get_path(audio_id);
-        stream_to_file(&path, stream).await?;
+    async fn store(&self, key: &str, stream: AudioStream) -> StorageResult<()> {
+        let path = self.get_path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("failed to create the audio directory")?;
+        }
+        let expected_length = stream.expected_length;
+        let written = match stream_to_file(&path, stream).await {
+            Ok(written) => written,
+            Err(err) => {
+                let err = StorageError::from(err);
+                if let StorageError::Full(_) = err {
+                    remove_partial_file(&path).await;
+                }
+                return Err(err);
+            }
+        };
+
+        // A full disk can also show up as a silently truncated file
+        if let Some(expected_length) = expected_length {
+            if written != expected_length {
+                remove_partial_file(&path).await;
+                return Err(StorageError::Full(anyhow::anyhow!(
+                    "wrote {written} bytes of {expected_length} to {}",
+                    path.display()
+                )));
+            }
+        }
+
         Ok(())
     }
 
-    async fn delete(&self, audio_id: i32) -> anyhow::Result<()> {
-        tokio::fs::remove_file(self.get_path(audio_id)).await?;
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        tokio::fs::remove_file(self.find_path(key).await?).await?;
         Ok(())
     }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        match self.find_path(key).await {
+            Ok(_) => Ok(true),
+            Err(StorageError::NotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn size(&self, key: &str) -> StorageResult<u64> {
+        let metadata = tokio::fs::metadata(self.find_path(key).await?).await?;
+        Ok(metadata.len())
+    }
+
+    async fn list(&self) -> StorageResult<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut entries = tokio::fs::read_dir(UPLOADS_DIRECTORY)
+            .await
+            .context("failed to read the uploads directory")?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    keys.push(name.to_string());
+                }
+            } else if file_type.is_dir() {
+                let mut shard_entries = tokio::fs::read_dir(entry.path())
+                    .await
+                    .context("failed to read an uploads subdirectory")?;
+                while let Some(entry) = shard_entries.next_entry().await? {
+                    if entry.file_type().await?.is_file() {
+                        if let Some(name) = entry.file_name().to_str() {
+                            keys.push(name.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn health_check(&self) -> StorageResult<()> {
+        let path = Path::new(UPLOADS_DIRECTORY).join(HEALTH_CHECK_FILE);
+        tokio::fs::write(&path, b"ok")
+            .await
+            .context("uploads directory is not writable")?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// There is no audio with the key
+    NotFound,
+    /// The storage has no space left for the audio
+    Full(anyhow::Error),
+    /// The storage could not be reached, retrying later might work
+    Unavailable(anyhow::Error),
+    /// The backend can't do the operation
+    Unsupported,
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "audio not found in storage"),
+            StorageError::Full(err) => write!(f, "no space left in storage: {err:#}"),
+            StorageError::Unavailable(err) => write!(f, "storage unavailable: {err:#}"),
+            StorageError::Unsupported => write!(f, "not supported by the storage backend"),
+            StorageError::Other(err) => write!(f, "{err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => StorageError::NotFound,
+            io::ErrorKind::StorageFull | io::ErrorKind::WriteZero => StorageError::Full(err.into()),
+            _ => StorageError::Other(err.into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for StorageError {
+    fn from(err: anyhow::Error) -> Self {
+        let io_kind = err
+            .chain()
+            .find_map(|err| err.downcast_ref::<io::Error>())
+            .map(io::Error::kind);
+        match io_kind {
+            Some(io::ErrorKind::StorageFull | io::ErrorKind::WriteZero) => StorageError::Full(err),
+            _ => StorageError::Other(err),
+        }
+    }
+}
+
+impl From<azure_core::Error> for StorageError {
+    fn from(err: azure_core::Error) -> Self {
+        match err.kind() {
+            azure_core::error::ErrorKind::HttpResponse { status, .. } => {
+                if *status == azure_core::StatusCode::NotFound {
+                    StorageError::NotFound
+                } else if status.is_server_error() {
+                    StorageError::Unavailable(err.into())
+                } else {
+                    StorageError::Other(err.into())
+                }
+            }
+            azure_core::error::ErrorKind::Io => StorageError::Unavailable(err.into()),
+            _ => StorageError::Other(err.into()),
+        }
+    }
+}
+
+async fn remove_partial_file(path: &Path) {
+    if let Err(err) = tokio::fs::remove_file(path).await {
+        tracing::error!(?err, path = %path.display(), "failed to remove partial file");
+    }
 }
 
 impl LocalAudioStorage {
-    fn get_path(&self, audio_id: i32) -> PathBuf {
+    /// Where `key` is stored from now on
+    fn get_path(&self, key: &str) -> PathBuf {
         // TODO: use file's sha256 as path
-        std::path::Path::new(UPLOADS_DIRECTORY)
-            .join(format!("{}{}", audio_id, AUDIO_FILE_EXTENSION))
+        if self.sharded {
+            Path::new(UPLOADS_DIRECTORY).join(shard(key)).join(key)
+        } else {
+            Path::new(UPLOADS_DIRECTORY).join(key)
+        }
+    }
+
+    /// Where `key` is currently stored, falling back to the flat path for
+    /// files stored before sharding was enabled
+    async fn find_path(&self, key: &str) -> StorageResult<PathBuf> {
+        let path = self.get_path(key);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(path);
+        }
+        let flat_path = Path::new(UPLOADS_DIRECTORY).join(key);
+        if self.sharded && tokio::fs::try_exists(&flat_path).await? {
+            return Ok(flat_path);
+        }
+        Err(StorageError::NotFound)
     }
 }
 
+/// The subdirectory of a sharded local storage that `key` belongs to, one of
+/// 256. Uses FNV-1a so it doesn't change between builds.
+fn shard(key: &str) -> String {
+    let hash = key.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    format!("{:02x}", hash & 0xff)
+}
+
 impl AzureAudioStorage {
-    pub fn new(account: &str, access_key: &str, container: &str) -> AzureAudioStorage {
+    /// Uploads are sent in blocks of `block_size` bytes, except for the last one
+    pub fn new(
+        account: &str,
+        access_key: &str,
+        container: &str,
+        block_size: usize,
+    ) -> AzureAudioStorage {
         let storage_credentials = StorageCredentials::access_key(account, access_key.to_string());
         AzureAudioStorage {
             storage_credentials,
-            account: account.to_string(),
+            location: CloudLocation::Public {
+                account: account.to_string(),
+            },
             container: container.to_string(),
+            block_size,
         }
     }
 
-    fn get_client(&self, audio_id: i32) -> BlobClient {
-        let blob_name = format!("{}{}", audio_id, AUDIO_FILE_EXTENSION);
-        ClientBuilder::new(&self.account, self.storage_credentials.clone())
-            .blob_client(&self.container, blob_name)
+    /// Like [`AzureAudioStorage::new`], but takes the account and credentials from an azure
+    /// storage connection string. Its `BlobEndpoint` is used instead of the public cloud when
+    /// set, like with Azurite or a custom domain
+    pub fn new_from_connection_string(
+        connection_string: &str,
+        container: &str,
+        block_size: usize,
+    ) -> anyhow::Result<AzureAudioStorage> {
+        let connection_string = ConnectionString::new(connection_string)?;
+        let location = match (
+            connection_string.blob_endpoint,
+            connection_string.account_name,
+        ) {
+            (Some(endpoint), _) => CloudLocation::Custom {
+                uri: endpoint.trim_end_matches('/').to_string(),
+            },
+            (None, Some(account)) => CloudLocation::Public {
+                account: account.to_string(),
+            },
+            (None, None) => anyhow::bail!(
+                "azure storage connection string is missing AccountName or BlobEndpoint"
+            ),
+        };
+        let storage_credentials = connection_string.storage_credentials()?;
+        Ok(AzureAudioStorage {
+            storage_credentials,
+            location,
+            container: container.to_string(),
+            block_size,
+        })
+    }
+
+    fn client_builder(&self) -> ClientBuilder {
+        ClientBuilder::with_location(self.location.clone(), self.storage_credentials.clone())
+    }
+
+    fn get_client(&self, key: &str) -> BlobClient {
+        self.client_builder().blob_client(&self.container, key)
     }
 }
 
 #[async_trait]
 impl AudioStorage for AzureAudioStorage {
-    async fn get(&self, audio_id: i32) -> anyhow::Result<AudioStream> {
-        let blob_client = self.get_client(audio_id);
+    async fn get(&self, key: &str) -> StorageResult<AudioStream> {
+        let blob_client = self.get_client(key);
+        // The download is lazy, without this a missing blob would only fail
+        // once the response is already being streamed
+        blob_client.get_properties().await?;
         let stream = blob_client
             .get()
             .chunk_size(2u64 * 1024 * 1024)
@@ -108,54 +474,143 @@ impl AudioStorage for AzureAudioStorage {
         Ok(AudioStream::from_pageable(stream))
     }
 
-    async fn store(&self, audio_id: i32, mut stream: BodyStream) -> anyhow::Result<()> {
-        let blob_client = self.get_client(audio_id);
+    async fn store(&self, key: &str, mut stream: AudioStream) -> StorageResult<()> {
+        let blob_client = self.get_client(key);
+        let content_type = content_type_of(key)
+            .with_context(|| format!("unknown content type for {key}"))
+            .map_err(StorageError::Other)?;
 
         let mut block_list = BlockList::default();
+        let mut buffer = BytesMut::with_capacity(self.block_size);
 
-        let mut i = 0;
-        while let Some(chunk) = stream.next().await {
-            let bytes = chunk?;
-            let block_id = format!("{:08X}", i);
-            blob_client.put_block(block_id.clone(), bytes).await?;
-            i += 1;
-            block_list
-                .blocks
-                .push(BlobBlockType::new_uncommitted(block_id));
+        let mut ended = false;
+        while !ended {
+            match stream.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => ended = true,
+            }
+
+            while buffer.len() >= self.block_size || (ended && !buffer.is_empty()) {
+                let len = buffer.len().min(self.block_size);
+                let block = buffer.split_to(len).freeze();
+                let block_id = format!("{:08X}", block_list.blocks.len());
+                blob_client.put_block(block_id.clone(), block).await?;
+                block_list
+                    .blocks
+                    .push(BlobBlockType::new_uncommitted(block_id));
+            }
         }
         blob_client
             .put_block_list(block_list)
-            .content_type(AUDIO_FILE_MIMETYPE)
+            .content_type(content_type)
             .await?;
 
         Ok(())
     }
 
-    async fn delete(&self, audio_id: i32) -> anyhow::Result<()> {
-        let blob_client = self.get_client(audio_id);
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        let blob_client = self.get_client(key);
         blob_client.delete().await?;
         Ok(())
     }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        Ok(self.get_client(key).exists().await?)
+    }
+
+    async fn size(&self, key: &str) -> StorageResult<u64> {
+        let properties = self.get_client(key).get_properties().await?;
+        Ok(properties.blob.properties.content_length)
+    }
+
+    /// Uploads must be a single Put Blob request with `x-ms-blob-type: BlockBlob`
+    async fn presign(
+        &self,
+        key: &str,
+        action: PresignAction,
+        expires_at: DateTime<Utc>,
+    ) -> StorageResult<String> {
+        let permissions = match action {
+            PresignAction::Upload => BlobSasPermissions {
+                create: true,
+                write: true,
+                ..Default::default()
+            },
+            PresignAction::Download => BlobSasPermissions {
+                read: true,
+                ..Default::default()
+            },
+        };
+        let expiry = time::OffsetDateTime::from_unix_timestamp(expires_at.timestamp())
+            .context("invalid presigned url expiration")?;
+        let blob_client = self.get_client(key);
+        let signature = blob_client
+            .shared_access_signature(permissions, expiry)
+            .await?;
+        Ok(blob_client
+            .generate_signed_blob_url(&signature)?
+            .to_string())
+    }
+
+    async fn list(&self) -> StorageResult<Vec<String>> {
+        let container_client = self.client_builder().container_client(&self.container);
+        let mut pages = container_client.list_blobs().into_stream();
+        let mut keys = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page?;
+            keys.extend(page.blobs.blobs().map(|blob| blob.name.clone()));
+        }
+        Ok(keys)
+    }
+
+    async fn health_check(&self) -> StorageResult<()> {
+        let container_client = self.client_builder().container_client(&self.container);
+        let mut pages = container_client
+            .list_blobs()
+            .max_results(NonZeroU32::MIN)
+            .into_stream();
+        if let Some(page) = pages.next().await {
+            page?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl AudioStorage for MockAudioStorage {
-    async fn get(&self, audio_id: i32) -> anyhow::Result<AudioStream> {
-        tracing::info!("retrieving audio file {audio_id}");
-        let file = tokio::task::spawn_blocking(tempfile::tempfile).await??;
+    async fn get(&self, key: &str) -> StorageResult<AudioStream> {
+        tracing::info!("retrieving audio file {key}");
+        let file = tokio::task::spawn_blocking(tempfile::tempfile)
+            .await
+            .context("failed to create tempfile")??;
         let file = tokio::fs::File::from_std(file);
         Ok(AudioStream::from_file(file))
     }
 
-    async fn store(&self, audio_id: i32, _stream: BodyStream) -> anyhow::Result<()> {
-        tracing::info!("storing audio {audio_id}");
+    async fn store(&self, key: &str, _stream: AudioStream) -> StorageResult<()> {
+        tracing::info!("storing audio {key}");
         Ok(())
     }
 
-    async fn delete(&self, audio_id: i32) -> anyhow::Result<()> {
-        tracing::info!("deleting audio {audio_id}");
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        tracing::info!("deleting audio {key}");
         Ok(())
     }
+
+    async fn list(&self) -> StorageResult<Vec<String>> {
+        tracing::info!("listing audios");
+        Ok(Vec::new())
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        tracing::info!("checking audio {key}");
+        Ok(true)
+    }
+
+    async fn size(&self, key: &str) -> StorageResult<u64> {
+        tracing::info!("getting size of audio {key}");
+        Ok(0)
+    }
 }
 
 // Save a `Stream` to a file
@@ -166,7 +621,7 @@ where
 {
     async {
         // Convert the stream into an `AsyncRead`.
-        let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        let body_with_io_error = stream.map_err(io::Error::other);
         let body_reader = StreamReader::new(body_with_io_error);
         futures::pin_mut!(body_reader);
 
@@ -184,6 +639,7 @@ where
 
 pub struct AudioStream {
     stream: Pin<Box<dyn Stream<Item = anyhow::Result<Bytes>> + Send + 'static>>,
+    expected_length: Option<u64>,
 }
 
 impl AudioStream {
@@ -207,15 +663,69 @@ impl AudioStream {
         });
         AudioStream {
             stream: Box::pin(stream),
+            expected_length: None,
+        }
+    }
+
+    pub fn from_bytes(bytes: Bytes) -> AudioStream {
+        let length = bytes.len().try_into().ok();
+        let stream = futures::stream::once(async move { Ok(bytes) });
+        AudioStream {
+            stream: Box::pin(stream),
+            expected_length: length,
+        }
+    }
+
+    /// Read at least `length` bytes from the start of the stream, or all of it
+    /// if it is shorter, without consuming them
+    pub async fn peek(mut self, length: usize) -> anyhow::Result<(Bytes, AudioStream)> {
+        let mut prefix = BytesMut::new();
+        while prefix.len() < length {
+            match self.stream.next().await {
+                Some(bytes) => prefix.put(bytes?),
+                None => break,
+            }
+        }
+        let prefix = prefix.freeze();
+        let stream = futures::stream::once({
+            let prefix = prefix.clone();
+            async move { Ok(prefix) }
+        })
+        .chain(self.stream);
+        Ok((
+            prefix,
+            AudioStream {
+                stream: Box::pin(stream),
+                expected_length: self.expected_length,
+            },
+        ))
+    }
+
+    /// Set how many bytes the stream is expected to have, if known
+    pub fn expected_length(mut self, expected_length: Option<u64>) -> AudioStream {
+        self.expected_length = expected_length;
+        self
+    }
+
+    pub fn from_stream<S, E>(stream: S) -> AudioStream
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<anyhow::Error>,
+    {
+        let stream = stream.map(|value| value.map_err(Into::into));
+        AudioStream {
+            stream: Box::pin(stream),
+            expected_length: None,
         }
     }
 
-    fn from_file(file: File) -> AudioStream {
+    pub fn from_file(file: File) -> AudioStream {
         let stream =
             ReaderStream::new(file).map(|value| value.map_err(Into::<anyhow::Error>::into));
         let stream = Box::new(stream);
         AudioStream {
             stream: Box::pin(stream),
+            expected_length: None,
         }
     }
 }
@@ -236,3 +746,77 @@ impl std::fmt::Debug for AudioStream {
         write!(f, "AudioStream")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn azure_connection_string_blob_endpoint() {
+        let storage = AzureAudioStorage::new_from_connection_string(
+            "DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=a2V5;\
+             BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1/;",
+            "audios",
+            1024,
+        )
+        .unwrap();
+        let url = storage.get_client("1.webm").url().unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://127.0.0.1:10000/devstoreaccount1/audios/1.webm"
+        );
+
+        let storage = AzureAudioStorage::new_from_connection_string(
+            "AccountName=account;AccountKey=a2V5",
+            "audios",
+            1024,
+        )
+        .unwrap();
+        let url = storage.get_client("1.webm").url().unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://account.blob.core.windows.net/audios/1.webm"
+        );
+    }
+
+    #[test]
+    fn media_type_ignores_parameters() {
+        assert_eq!(media_type("audio/webm").as_deref(), Some("audio/webm"));
+        assert_eq!(
+            media_type("audio/webm;codecs=opus").as_deref(),
+            Some("audio/webm")
+        );
+        assert_eq!(
+            media_type("audio/ogg; codecs=\"opus\"").as_deref(),
+            Some("audio/ogg")
+        );
+        assert_eq!(media_type(" Audio/WebM ").as_deref(), Some("audio/webm"));
+    }
+
+    #[test]
+    fn media_type_rejects_invalid() {
+        assert_eq!(media_type(""), None);
+        assert_eq!(media_type("webm"), None);
+        assert_eq!(media_type("audio/webm;codecs"), None);
+    }
+
+    #[test]
+    fn file_extension_of_parameterized_type() {
+        assert_eq!(file_extension("audio/webm;codecs=opus"), Some(".webm"));
+        assert_eq!(file_extension("audio/mp4; codecs=mp4a.40.2"), Some(".m4a"));
+        assert_eq!(file_extension("video/webm;codecs=vp8"), None);
+    }
+
+    #[test]
+    fn sniffs_supported_containers() {
+        assert_eq!(
+            sniff_content_type(&[0x1a, 0x45, 0xdf, 0xa3, 0x01]),
+            Some("audio/webm")
+        );
+        assert_eq!(sniff_content_type(b"OggS\0\x02"), Some("audio/ogg"));
+        assert_eq!(sniff_content_type(b"RIFF\0\0\0\0WAVE"), Some("audio/wav"));
+        assert_eq!(sniff_content_type(b"\0\0\0\x20ftypM4A "), Some("audio/mp4"));
+        assert_eq!(sniff_content_type(b"<html>"), None);
+        assert_eq!(sniff_content_type(b""), None);
+    }
+}
@@ -1,24 +1,64 @@
 use anyhow::Context;
 use axum::{
     async_trait,
-    extract::{FromRequestParts, TypedHeader},
-    headers::{authorization::Bearer, Authorization},
-    http::request::Parts,
+    extract::FromRequestParts,
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{request::Parts, HeaderMap},
     Extension, RequestPartsExt,
 };
 use jsonwebtoken::{decode, Validation};
 use serde::{Deserialize, Serialize};
 
-use crate::{ApiError, AppState};
+use crate::{database, ApiError, AppState, Keys};
 
 #[derive(Deserialize, Serialize)]
 pub struct Claims {
     pub user_id: i32,
     pub email: String,
     pub language: String,
+    /// Missing in tokens issued before avatars existed
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    /// Missing in tokens issued before timezones existed
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Missing in tokens issued before scopes existed, which have full access
+    #[serde(default)]
+    pub scope: Scope,
     pub exp: i64,
 }
 
+/// What a token is allowed to do
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    #[default]
+    Full,
+    /// Only reading, writes are rejected by [`WriteClaims`]
+    ReadOnly,
+}
+
+/// Claims of an authenticated user with a token that is allowed to write
+pub struct WriteClaims(pub Claims);
+
+/// Claims of an authenticated user that is an admin, with a token that is
+/// allowed to write
+pub struct AdminClaims(pub Claims);
+
+impl Claims {
+    /// Decode the claims from the bearer token in `headers`, if there's a valid one
+    pub fn from_headers(headers: &HeaderMap, keys: &Keys) -> Option<Claims> {
+        let Authorization(bearer) = headers.typed_get::<Authorization<Bearer>>()?;
+        Claims::decode(bearer.token(), keys)
+    }
+
+    fn decode(token: &str, keys: &Keys) -> Option<Claims> {
+        decode::<Claims>(token, &keys.decoding, &Validation::default())
+            .ok()
+            .map(|token_data| token_data.claims)
+    }
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for Claims
 where
@@ -27,22 +67,201 @@ where
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
+        let Extension(state) = parts
+            .extract::<Extension<AppState>>()
             .await
-            .map_err(|_| ApiError::Unauthorized)?;
+            .context("failed to get AppState in Claims FromRequestParts")?;
+
+        Claims::from_headers(&parts.headers, &state.keys).ok_or(ApiError::Unauthorized)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WriteClaims
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
+        if claims.scope != Scope::Full {
+            return Err(ApiError::Forbidden);
+        }
+        Ok(WriteClaims(claims))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminClaims
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let WriteClaims(claims) = WriteClaims::from_request_parts(parts, state).await?;
 
         let Extension(state) = parts
             .extract::<Extension<AppState>>()
             .await
-            .context("failed to get AppState in Claims FromRequestParts")?;
+            .context("failed to get AppState in AdminClaims FromRequestParts")?;
+
+        // Checked on every request so revoking admin takes effect immediately
+        if !database::is_user_admin(&state.pool, claims.user_id).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        Ok(AdminClaims(claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header::AUTHORIZATION, Request};
+    use chrono::{Duration, Utc};
+    use jsonwebtoken::{encode, Header};
+
+    use crate::test_app_state;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn claims(exp: i64) -> Claims {
+        Claims {
+            user_id: 1,
+            email: "user@example.com".to_string(),
+            language: "en".to_string(),
+            avatar_url: None,
+            timezone: None,
+            scope: Scope::Full,
+            exp,
+        }
+    }
+
+    fn token(claims: &Claims, secret: &[u8]) -> String {
+        encode(
+            &Header::default(),
+            claims,
+            &Keys::from_secret(secret).encoding,
+        )
+        .unwrap()
+    }
+
+    fn headers(authorization: Option<&str>) -> HeaderMap {
+        let mut request = Request::builder();
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        request.body(()).unwrap().into_parts().0.headers
+    }
+
+    fn extract(authorization: Option<&str>) -> Option<Claims> {
+        Claims::from_headers(&headers(authorization), &Keys::from_secret(SECRET))
+    }
+
+    #[test]
+    fn rejects_missing_header() {
+        assert!(extract(None).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_token() {
+        assert!(extract(Some("Bearer not.a.token")).is_none());
+        assert!(extract(Some("Bearer")).is_none());
+        let valid = token(
+            &claims((Utc::now() + Duration::days(1)).timestamp()),
+            SECRET,
+        );
+        assert!(extract(Some(&format!("Basic {valid}"))).is_none());
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let expired = token(
+            &claims((Utc::now() - Duration::hours(1)).timestamp()),
+            SECRET,
+        );
+        assert!(extract(Some(&format!("Bearer {expired}"))).is_none());
+    }
+
+    #[test]
+    fn rejects_token_signed_with_other_secret() {
+        let other = token(
+            &claims((Utc::now() + Duration::days(1)).timestamp()),
+            b"other",
+        );
+        assert!(extract(Some(&format!("Bearer {other}"))).is_none());
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let exp = (Utc::now() + Duration::days(1)).timestamp();
+        let valid = token(&claims(exp), SECRET);
+        let extracted = extract(Some(&format!("Bearer {valid}"))).unwrap();
+        assert_eq!(extracted.user_id, 1);
+        assert_eq!(extracted.email, "user@example.com");
+        assert_eq!(extracted.language, "en");
+        assert_eq!(extracted.exp, exp);
+    }
+
+    /// Parts of a request to be extracted from, with the state of the app
+    fn parts(state: &AppState, authorization: Option<&str>) -> Parts {
+        let mut request = Request::builder();
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let mut parts = request.body(()).unwrap().into_parts().0;
+        parts.extensions.insert(state.clone());
+        parts
+    }
+
+    fn bearer(state: &AppState, scope: Scope) -> String {
+        let claims = Claims {
+            scope,
+            ..claims((Utc::now() + Duration::days(1)).timestamp())
+        };
+        let token = encode(&Header::default(), &claims, &state.keys.encoding).unwrap();
+        format!("Bearer {token}")
+    }
+
+    #[tokio::test]
+    async fn extractor_rejects_missing_token() {
+        let state = test_app_state();
+        let result = Claims::from_request_parts(&mut parts(&state, None), &()).await;
+        assert!(matches!(result, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn extractor_rejects_invalid_token() {
+        let state = test_app_state();
+        let mut parts = parts(&state, Some("Bearer not.a.token"));
+        let result = Claims::from_request_parts(&mut parts, &()).await;
+        assert!(matches!(result, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn extractor_accepts_valid_token() {
+        let state = test_app_state();
+        let authorization = bearer(&state, Scope::Full);
+        let mut parts = parts(&state, Some(&authorization));
+        let claims = Claims::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(claims.user_id, 1);
+    }
 
-        // Decode the user data
-        let token_data =
-            decode::<Claims>(bearer.token(), &state.keys.decoding, &Validation::default())
-                .map_err(|_| ApiError::Unauthorized)?;
+    #[tokio::test]
+    async fn write_claims_reject_read_only_scope() {
+        let state = test_app_state();
+        let read_only = bearer(&state, Scope::ReadOnly);
+        let result =
+            WriteClaims::from_request_parts(&mut parts(&state, Some(&read_only)), &()).await;
+        assert!(matches!(result, Err(ApiError::Forbidden)));
 
-        Ok(token_data.claims)
+        let full = bearer(&state, Scope::Full);
+        let WriteClaims(claims) =
+            WriteClaims::from_request_parts(&mut parts(&state, Some(&full)), &())
+                .await
+                .unwrap();
+        assert_eq!(claims.scope, Scope::Full);
     }
 }
@@ -0,0 +1,203 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use ring::digest::{digest, SHA256};
+use sqlx::PgPool;
+
+use crate::{
+    audio_storage::{audio_key, AudioStorage, AudioStream, StorageBackend},
+    database, init_storage,
+    routes::users::{check_new_password, hash},
+    ApiError, Config,
+};
+
+/// Without a command the server is started
+#[derive(Parser)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Copy every audio from one storage backend to another.
+    ///
+    /// Audios already in the destination with the same checksum are skipped,
+    /// so an interrupted migration can be resumed by running it again.
+    MigrateStorage {
+        #[arg(long, value_enum)]
+        from: StorageBackend,
+        #[arg(long, value_enum)]
+        to: StorageBackend,
+    },
+    /// Create a user, prompting for its password.
+    CreateUser {
+        #[arg(long)]
+        email: String,
+        #[arg(long, default_value = "en")]
+        language: String,
+        /// IANA name of the user's timezone, like "America/New_York"
+        #[arg(long)]
+        timezone: Option<String>,
+        /// Allow the user to access the admin endpoints
+        #[arg(long)]
+        admin: bool,
+    },
+}
+
+pub async fn run(command: Command, config: &Config, pool: &PgPool) -> anyhow::Result<()> {
+    match command {
+        Command::MigrateStorage { from, to } => migrate_storage(config, pool, from, to).await,
+        Command::CreateUser {
+            email,
+            language,
+            timezone,
+            admin,
+        } => create_user(config, pool, &email, &language, timezone.as_deref(), admin).await,
+    }
+}
+
+async fn migrate_storage(
+    config: &Config,
+    pool: &PgPool,
+    from: StorageBackend,
+    to: StorageBackend,
+) -> anyhow::Result<()> {
+    if from == to {
+        anyhow::bail!("source and destination storage are the same");
+    }
+
+    let source = init_storage(config, from).await?;
+    let destination = init_storage(config, to).await?;
+
+    let audios = database::get_all_audios(pool, Utc::now()).await?;
+    let existing_keys = destination
+        .list()
+        .await
+        .context("failed to list destination storage")?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let total = audios.len();
+    let mut copied = 0;
+    let mut skipped = 0;
+    let mut failed = 0;
+    for (i, audio) in audios.iter().enumerate() {
+        let key = audio_key(audio);
+        let progress = format!("[{}/{total}]", i + 1);
+
+        let result = copy_audio(
+            source.as_ref(),
+            destination.as_ref(),
+            &key,
+            existing_keys.contains(&key),
+        )
+        .await;
+        match result {
+            Ok(true) => {
+                copied += 1;
+                tracing::info!("{progress} copied {key}");
+            }
+            Ok(false) => {
+                skipped += 1;
+                tracing::info!("{progress} skipped {key}, already in destination");
+            }
+            Err(err) => {
+                failed += 1;
+                tracing::error!(?err, "{progress} failed to copy {key}");
+            }
+        }
+    }
+
+    tracing::info!(copied, skipped, failed, "finished migrating storage");
+    if failed > 0 {
+        anyhow::bail!("failed to copy {failed} audios, run the migration again to retry them");
+    }
+    Ok(())
+}
+
+/// Copy the audio at `key`, returns false if the destination already had it
+async fn copy_audio(
+    source: &(dyn AudioStorage + Send + Sync),
+    destination: &(dyn AudioStorage + Send + Sync),
+    key: &str,
+    in_destination: bool,
+) -> anyhow::Result<bool> {
+    let bytes = source.get(key).await?.into_bytes().await?;
+    let checksum = digest(&SHA256, &bytes);
+
+    if in_destination {
+        let existing = destination.get(key).await?.into_bytes().await?;
+        if digest(&SHA256, &existing).as_ref() == checksum.as_ref() {
+            return Ok(false);
+        }
+    }
+
+    destination
+        .store(key, AudioStream::from_bytes(bytes))
+        .await?;
+
+    let copied = destination.get(key).await?.into_bytes().await?;
+    if digest(&SHA256, &copied).as_ref() != checksum.as_ref() {
+        anyhow::bail!("checksum mismatch after copying {key}");
+    }
+
+    Ok(true)
+}
+
+async fn create_user(
+    config: &Config,
+    pool: &PgPool,
+    email: &str,
+    language: &str,
+    timezone: Option<&str>,
+    admin: bool,
+) -> anyhow::Result<()> {
+    if !config.is_language_allowed(language) {
+        anyhow::bail!("language {language:?} is not allowed");
+    }
+    if let Some(timezone) = timezone {
+        if timezone.parse::<chrono_tz::Tz>().is_err() {
+            anyhow::bail!("unknown timezone {timezone:?}");
+        }
+    }
+    if database::find_user_by_email(pool, email).await?.is_some() {
+        anyhow::bail!("a user with email {email:?} already exists");
+    }
+
+    let password = rpassword::prompt_password("Password: ")?;
+    if let Err(err) = check_new_password(config, "password", &password, &[email]) {
+        match err {
+            ApiError::ValidationError(errors) => {
+                for error in errors {
+                    eprintln!("{}: {}", error.field, error.message);
+                }
+            }
+            ApiError::WeakPassword(feedback) => {
+                if let Some(warning) = feedback.warning() {
+                    eprintln!("{warning}");
+                }
+                for suggestion in feedback.suggestions() {
+                    eprintln!("{suggestion}");
+                }
+            }
+            err => return Err(anyhow::anyhow!("failed to check password: {err:?}")),
+        }
+        anyhow::bail!("password is too weak");
+    }
+    if rpassword::prompt_password("Confirm password: ")? != password {
+        anyhow::bail!("passwords do not match");
+    }
+
+    let password_hash = hash(&password)?;
+    let id = database::create_user(pool, email, language, timezone, &password_hash, admin).await?;
+    tracing::info!(
+        id,
+        admin,
+        "created user {}",
+        database::normalize_email(email)
+    );
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts, HeaderMap},
+    Extension, RequestPartsExt,
+};
+
+use crate::{ApiError, AppState};
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Information about the client that made a request
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extract::<ConnectInfo<SocketAddr>>()
+            .await
+            .context("failed to get ConnectInfo in ClientInfo FromRequestParts")?;
+
+        let Extension(state) = parts
+            .extract::<Extension<AppState>>()
+            .await
+            .context("failed to get AppState in ClientInfo FromRequestParts")?;
+
+        let ip = client_ip(&parts.headers, peer.ip(), &state.config.trusted_proxies);
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string);
+
+        Ok(ClientInfo { ip, user_agent })
+    }
+}
+
+/// Get the ip of the client, only trusting `X-Forwarded-For` when the request
+/// comes from one of the `trusted_proxies`. IPv4 clients of a `DUAL_STACK`
+/// socket show up as IPv4-mapped IPv6 addresses, so addresses are compared and
+/// returned in their canonical form
+fn client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let peer = peer.to_canonical();
+    let is_trusted = |ip: &IpAddr| {
+        trusted_proxies
+            .iter()
+            .any(|proxy| proxy.to_canonical() == *ip)
+    };
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let forwarded_for = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect::<Vec<_>>();
+
+    // Each proxy appends the address it got the request from, so walk from the
+    // right and stop at the first address that isn't one of our proxies
+    forwarded_for
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or(forwarded_for.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn canonicalizes_ipv4_mapped_peers() {
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let mapped_proxy: IpAddr = "::ffff:10.0.0.2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            FORWARDED_FOR_HEADER,
+            HeaderValue::from_static("::ffff:203.0.113.7"),
+        );
+
+        let ip = client_ip(&headers, mapped_proxy, &[proxy]);
+        assert_eq!(ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+
+        let ip = client_ip(&headers, "::ffff:198.51.100.1".parse().unwrap(), &[proxy]);
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+}
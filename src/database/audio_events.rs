@@ -0,0 +1,45 @@
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+
+pub const AUDIO_EVENT_UPLOADED: &str = "uploaded";
+pub const AUDIO_EVENT_TRANSCRIPTION_STARTED: &str = "transcription_started";
+pub const AUDIO_EVENT_TRANSCRIPTION_FAILED: &str = "transcription_failed";
+pub const AUDIO_EVENT_COMPLETED: &str = "completed";
+/// A transcription is attempted again after failing
+pub const AUDIO_EVENT_RETRIED: &str = "retried";
+
+#[derive(FromRow)]
+pub struct DbAudioEvent {
+    pub id: i32,
+    pub event_type: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The events of an audio, oldest first
+pub async fn get_audio_events(pool: &PgPool, audio_id: i32) -> sqlx::Result<Vec<DbAudioEvent>> {
+    sqlx::query_as(
+        "select id, event_type, error, created_at
+         from audio_events
+         where audio_id = $1
+         order by id",
+    )
+    .bind(audio_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_audio_event(
+    pool: &PgPool,
+    audio_id: i32,
+    event_type: &str,
+    error: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query("insert into audio_events (audio_id, event_type, error) values ($1, $2, $3)")
+        .bind(audio_id)
+        .bind(event_type)
+        .bind(error)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
@@ -1,46 +1,386 @@
 use chrono::{DateTime, Utc};
-use sqlx::{FromRow, PgPool};
+use sqlx::{FromRow, PgExecutor, PgPool, Postgres, QueryBuilder};
+
+use super::{DbTag, DEFAULT_MAX_TRANSCRIPTION_RETRIES};
+
+/// Columns selected to build a [`DbAudio`]
+const AUDIO_COLUMNS: &str =
+    "id, transcription, created_at, user_id, language, title, user_agent, client_ip, content_type, transcription_status, transcription_provider, needs_review, storage_key, detected_language, transcription_word_count, transcription_char_count, transcription_model, updated_at";
+
+pub const TRANSCRIPTION_PENDING: &str = "pending";
+pub const TRANSCRIPTION_COMPLETED: &str = "completed";
+/// The audio is too short to be worth transcribing
+pub const TRANSCRIPTION_TOO_SHORT: &str = "too_short";
+/// The provider found nothing to transcribe, see `EMPTY_TRANSCRIPTIONS`
+pub const TRANSCRIPTION_EMPTY: &str = "empty";
+
+/// Transcription version sources that mean a person chose the transcription
+const HUMAN_SOURCES: &[&str] = &["human", "revert"];
 
 #[derive(FromRow)]
 pub struct DbAudio {
     pub id: i32,
     pub transcription: Option<String>,
     pub created_at: DateTime<Utc>,
-    pub user_id: i32,
+    /// None for guest audios that have not been claimed yet
+    pub user_id: Option<i32>,
+    pub language: String,
+    pub title: Option<String>,
+    pub user_agent: Option<String>,
+    pub client_ip: Option<String>,
+    pub content_type: String,
+    /// One of the `TRANSCRIPTION_*` statuses
+    pub transcription_status: String,
+    /// Speech to text provider that produced the transcription, "unknown" for
+    /// transcriptions made before this was recorded
+    pub transcription_provider: Option<String>,
+    /// The transcription has a low confidence and should be checked by the user
+    pub needs_review: bool,
+    /// Key of the stored file, None for audios keyed by their id
+    pub storage_key: Option<String>,
+    /// Language the speech to text provider detected in the audio, which
+    /// might differ from `language`
+    pub detected_language: Option<String>,
+    /// None until the audio is transcribed
+    pub transcription_word_count: Option<i32>,
+    pub transcription_char_count: Option<i32>,
+    /// Model chosen for the audio, None to use the default
+    pub transcription_model: Option<String>,
+    /// Last time the audio, its transcription or its tags changed
+    pub updated_at: DateTime<Utc>,
+}
+
+#[allow(dead_code)]
+#[derive(FromRow)]
+pub struct DbTranscriptionVersion {
+    pub id: i32,
+    pub audio_id: i32,
+    pub transcription: String,
+    pub source: String,
+    /// Speech to text provider that produced the text, None for versions
+    /// saved before providers were recorded in versions
+    pub provider: Option<String>,
+    pub created_at: DateTime<Utc>,
 }
 
+/// Transcription of one chunk of a long audio, kept until the whole audio is
+/// transcribed
+#[allow(dead_code)]
+#[derive(FromRow)]
+pub struct DbTranscriptionChunk {
+    pub audio_id: i32,
+    pub chunk_index: i32,
+    pub transcription: String,
+    pub confidence: Option<f32>,
+}
+
+pub struct NewAudio<'a> {
+    pub user_id: Option<i32>,
+    pub language: &'a str,
+    pub content_type: &'a str,
+    pub title: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub client_ip: Option<String>,
+    /// None to key the stored file by the audio id
+    pub storage_key: Option<String>,
+    /// Ignored by insert_guest_audio
+    pub transcription_model: Option<&'a str>,
+}
+
+#[allow(dead_code)]
 #[derive(FromRow)]
 pub struct DbFailedAudioTranscription {
     pub id: i32,
     pub audio_id: i32,
     pub retries: i32,
+    /// Retries allowed by the owner of the audio
+    pub max_retries: i16,
     pub language: String,
     pub created_at: DateTime<Utc>,
     pub last_retry_at: Option<DateTime<Utc>>,
 }
 
+pub async fn get_audio(pool: &PgPool, audio_id: i32) -> sqlx::Result<Option<DbAudio>> {
+    sqlx::query_as(&format!("select {AUDIO_COLUMNS} from audios where id = $1"))
+        .bind(audio_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Get every audio created before `created_before`, of all users
+pub async fn get_all_audios(
+    pool: &PgPool,
+    created_before: DateTime<Utc>,
+) -> sqlx::Result<Vec<DbAudio>> {
+    sqlx::query_as(&format!(
+        "select {AUDIO_COLUMNS} from audios where created_at < $1 order by id"
+    ))
+    .bind(created_before)
+    .fetch_all(pool)
+    .await
+}
+
+/// A row of [`get_audio_with_tags_by`], the tag columns are null for audios
+/// without tags
+#[derive(FromRow)]
+struct DbAudioTagRow {
+    #[sqlx(flatten)]
+    audio: DbAudio,
+    tag_id: Option<i32>,
+    tag_user_id: Option<i32>,
+    tag_name: Option<String>,
+    tag_color: Option<String>,
+}
+
+/// Like [`get_audio_by`], along with the tags of the audio, in a single query
+pub async fn get_audio_with_tags_by(
+    pool: &PgPool,
+    audio_id: i32,
+    user_id: i32,
+) -> sqlx::Result<Option<(DbAudio, Vec<DbTag>)>> {
+    let rows: Vec<DbAudioTagRow> = sqlx::query_as(&format!(
+        "select a.*, t.id as tag_id, t.user_id as tag_user_id, t.name as tag_name,
+                t.color as tag_color
+         from (
+             select {AUDIO_COLUMNS}
+             from audios
+             where id = $1 and user_id = $2
+         ) a
+         left join audio_tags l on l.audio_id = a.id
+         left join tags t on t.id = l.tag_id
+         order by t.id"
+    ))
+    .bind(audio_id)
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut rows = rows.into_iter();
+    let Some(first) = rows.next() else {
+        return Ok(None);
+    };
+    let tags = std::iter::once((
+        first.tag_id,
+        first.tag_user_id,
+        first.tag_name,
+        first.tag_color,
+    ))
+    .chain(rows.map(|row| (row.tag_id, row.tag_user_id, row.tag_name, row.tag_color)))
+    .filter_map(|(id, user_id, name, color)| {
+        Some(DbTag {
+            id: id?,
+            user_id: user_id?,
+            name: name?,
+            color,
+        })
+    })
+    .collect();
+    Ok(Some((first.audio, tags)))
+}
+
+pub async fn get_audio_by_storage_key(
+    pool: &PgPool,
+    storage_key: &str,
+) -> sqlx::Result<Option<DbAudio>> {
+    sqlx::query_as(&format!(
+        "select {AUDIO_COLUMNS} from audios where storage_key = $1"
+    ))
+    .bind(storage_key)
+    .fetch_optional(pool)
+    .await
+}
+
 pub async fn get_audio_by(
     pool: &PgPool,
     audio_id: i32,
     user_id: i32,
 ) -> sqlx::Result<Option<DbAudio>> {
-    sqlx::query_as(
-        "select id, transcription, created_at, user_id from audios where id = $1 and user_id = $2",
-    )
+    sqlx::query_as(&format!(
+        "select {AUDIO_COLUMNS}
+         from audios
+         where id = $1 and user_id = $2"
+    ))
     .bind(audio_id)
     .bind(user_id)
     .fetch_optional(pool)
     .await
 }
 
-pub async fn get_audios_by(pool: &PgPool, user_id: i32) -> sqlx::Result<Vec<DbAudio>> {
-    sqlx::query_as(
-        "select id, transcription, created_at, user_id
+/// Optional conditions of [`get_audios_by`] and [`count_audios_by`], every
+/// one given must match
+pub struct AudioFilter {
+    /// Words that must appear in the transcription
+    pub text_query: Option<String>,
+    /// Name of a tag of the audio
+    pub tag: Option<String>,
+    pub needs_review: Option<bool>,
+    /// Minimum words of the transcription
+    pub min_words: Option<i32>,
+    /// Created at or after
+    pub from: Option<DateTime<Utc>>,
+    /// Created before
+    pub to: Option<DateTime<Utc>>,
+    /// Changed after
+    pub modified_since: Option<DateTime<Utc>>,
+}
+
+/// Push the conditions of `filter` to a query selecting from `audios`
+fn push_audio_filter<'a>(
+    builder: &mut QueryBuilder<'a, Postgres>,
+    user_id: i32,
+    filter: &'a AudioFilter,
+) {
+    builder.push(" where user_id = ").push_bind(user_id);
+    if let Some(text_query) = &filter.text_query {
+        builder
+            .push(" and transcription_tsv @@ plainto_tsquery('simple', ")
+            .push_bind(text_query)
+            .push(")");
+    }
+    if let Some(tag) = &filter.tag {
+        builder
+            .push(
+                " and id in (
+                    select a.audio_id
+                    from audio_tags a
+                    join tags t on t.id = a.tag_id
+                    where t.user_id = ",
+            )
+            .push_bind(user_id)
+            .push(" and t.name = ")
+            .push_bind(tag)
+            .push(")");
+    }
+    if let Some(needs_review) = filter.needs_review {
+        builder.push(" and needs_review = ").push_bind(needs_review);
+    }
+    if let Some(min_words) = filter.min_words {
+        builder
+            .push(" and transcription_word_count >= ")
+            .push_bind(min_words);
+    }
+    if let Some(from) = filter.from {
+        builder.push(" and created_at >= ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        builder.push(" and created_at < ").push_bind(to);
+    }
+    if let Some(modified_since) = filter.modified_since {
+        builder.push(" and updated_at > ").push_bind(modified_since);
+    }
+}
+
+/// Characters of the transcription selected for snippets
+const TRANSCRIPTION_SNIPPET_LENGTH: i32 = 200;
+
+/// [`AUDIO_COLUMNS`] with only the first [`TRANSCRIPTION_SNIPPET_LENGTH`]
+/// characters of the transcription
+fn audio_snippet_columns() -> String {
+    AUDIO_COLUMNS
+        .split(", ")
+        .map(|column| {
+            if column == "transcription" {
+                format!("left(transcription, {TRANSCRIPTION_SNIPPET_LENGTH}) as transcription")
+            } else {
+                column.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Audios of the user matching `filter`, with only a snippet of their
+/// transcriptions if `snippets` is true
+pub async fn get_audios_by(
+    pool: &PgPool,
+    user_id: i32,
+    filter: &AudioFilter,
+    snippets: bool,
+    limit: i64,
+    offset: i64,
+) -> sqlx::Result<Vec<DbAudio>> {
+    let columns = if snippets {
+        audio_snippet_columns()
+    } else {
+        AUDIO_COLUMNS.to_string()
+    };
+    let mut builder = QueryBuilder::new(format!("select {columns} from audios"));
+    push_audio_filter(&mut builder, user_id, filter);
+    builder
+        .push(" order by id limit ")
+        .push_bind(limit)
+        .push(" offset ")
+        .push_bind(offset);
+    builder.build_query_as().fetch_all(pool).await
+}
+
+pub async fn count_audios_by(
+    pool: &PgPool,
+    user_id: i32,
+    filter: &AudioFilter,
+) -> sqlx::Result<i64> {
+    let mut builder = QueryBuilder::new("select count(*) from audios");
+    push_audio_filter(&mut builder, user_id, filter);
+    let count: (i64,) = builder.build_query_as().fetch_one(pool).await?;
+    Ok(count.0)
+}
+
+/// Audios created before `created_before` that have not been transcribed and
+/// are not being retried as failed transcriptions
+pub async fn get_pending_audios(
+    pool: &PgPool,
+    created_before: DateTime<Utc>,
+) -> sqlx::Result<Vec<DbAudio>> {
+    sqlx::query_as(&format!(
+        "select {AUDIO_COLUMNS}
+         from audios
+         where transcription_status = $1
+           and created_at < $2
+           and id not in (select audio_id from failed_audio_transcriptions)
+         order by id"
+    ))
+    .bind(TRANSCRIPTION_PENDING)
+    .bind(created_before)
+    .fetch_all(pool)
+    .await
+}
+
+/// Audios of the user with the tag named `tag` and created in
+/// `[created_from, created_to)`, a missing filter matches every audio. Audios
+/// whose current transcription was chosen by a person are skipped.
+pub async fn get_audios_to_retranscribe(
+    pool: &PgPool,
+    user_id: i32,
+    tag: Option<&str>,
+    created_from: Option<DateTime<Utc>>,
+    created_to: Option<DateTime<Utc>>,
+) -> sqlx::Result<Vec<DbAudio>> {
+    sqlx::query_as(&format!(
+        "select {AUDIO_COLUMNS}
          from audios
          where user_id = $1
-         order by id",
-    )
+           and ($2::text is null or id in (
+               select a.audio_id
+               from audio_tags a
+               join tags t on t.id = a.tag_id
+               where t.user_id = $1 and t.name = $2
+           ))
+           and ($3::timestamptz is null or created_at >= $3)
+           and ($4::timestamptz is null or created_at < $4)
+           and coalesce((
+               select source
+               from transcription_versions
+               where audio_id = audios.id
+               order by created_at desc, id desc
+               limit 1
+           ), '') <> all($5)
+         order by id"
+    ))
     .bind(user_id)
+    .bind(tag)
+    .bind(created_from)
+    .bind(created_to)
+    .bind(HUMAN_SOURCES)
     .fetch_all(pool)
     .await
 }
@@ -59,26 +399,160 @@ pub async fn get_failed_audio_transcription_retries(
     Ok(retries.map(|v| v.0))
 }
 
+pub async fn get_failed_audio_transcription_of(
+    pool: &PgPool,
+    audio_id: i32,
+) -> sqlx::Result<Option<i32>> {
+    let id: Option<(i32,)> = sqlx::query_as(
+        "select id from failed_audio_transcriptions
+         where audio_id = $1
+         order by id
+         limit 1",
+    )
+    .bind(audio_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(id.map(|v| v.0))
+}
+
+pub async fn get_failed_audio_transcription(
+    pool: &PgPool,
+    failed_audio_transcription_id: i32,
+) -> sqlx::Result<Option<DbFailedAudioTranscription>> {
+    sqlx::query_as(
+        "select f.id, f.audio_id, f.retries,
+                coalesce(u.max_transcription_retries, $2) as max_retries,
+                f.language, f.created_at, f.last_retry_at
+         from failed_audio_transcriptions f
+         join audios a on a.id = f.audio_id
+         left join users u on u.id = a.user_id
+         where f.id = $1",
+    )
+    .bind(failed_audio_transcription_id)
+    .bind(DEFAULT_MAX_TRANSCRIPTION_RETRIES)
+    .fetch_optional(pool)
+    .await
+}
+
 pub async fn get_failed_audio_transcriptions(
     pool: &PgPool,
 ) -> sqlx::Result<Vec<DbFailedAudioTranscription>> {
     sqlx::query_as(
-        "select id, audio_id, retries, language, created_at, last_retry_at
-         from failed_audio_transcriptions
-         order by id",
+        "select f.id, f.audio_id, f.retries,
+                coalesce(u.max_transcription_retries, $1) as max_retries,
+                f.language, f.created_at, f.last_retry_at
+         from failed_audio_transcriptions f
+         join audios a on a.id = f.audio_id
+         left join users u on u.id = a.user_id
+         order by f.id",
     )
+    .bind(DEFAULT_MAX_TRANSCRIPTION_RETRIES)
     .fetch_all(pool)
     .await
 }
 
-pub async fn insert_audio_by(pool: &PgPool, user_id: i32) -> sqlx::Result<i32> {
-    let id: (i32,) = sqlx::query_as("insert into audios(user_id) values ($1) returning id")
-        .bind(user_id)
-        .fetch_one(pool)
-        .await?;
+pub async fn insert_audio(pool: &PgPool, audio: &NewAudio<'_>) -> sqlx::Result<i32> {
+    let id: (i32,) = sqlx::query_as(
+        "insert into audios(user_id, language, content_type, title, user_agent, client_ip, storage_key, transcription_model)
+         values ($1, $2, $3, $4, $5, $6, $7, $8)
+         returning id",
+    )
+    .bind(audio.user_id)
+    .bind(audio.language)
+    .bind(audio.content_type)
+    .bind(audio.title)
+    .bind(audio.user_agent)
+    .bind(&audio.client_ip)
+    .bind(&audio.storage_key)
+    .bind(audio.transcription_model)
+    .fetch_one(pool)
+    .await?;
+    Ok(id.0)
+}
+
+/// Insert an audio without a user, which can be claimed with `claim_token`
+/// until `claim_expires_at`
+pub async fn insert_guest_audio(
+    pool: &PgPool,
+    audio: &NewAudio<'_>,
+    claim_token: &str,
+    claim_expires_at: DateTime<Utc>,
+) -> sqlx::Result<i32> {
+    let id: (i32,) = sqlx::query_as(
+        "insert into audios(language, content_type, title, user_agent, client_ip, storage_key, claim_token, claim_expires_at)
+         values ($1, $2, $3, $4, $5, $6, $7, $8)
+         returning id",
+    )
+    .bind(audio.language)
+    .bind(audio.content_type)
+    .bind(audio.title)
+    .bind(audio.user_agent)
+    .bind(&audio.client_ip)
+    .bind(&audio.storage_key)
+    .bind(claim_token)
+    .bind(claim_expires_at)
+    .fetch_one(pool)
+    .await?;
     Ok(id.0)
 }
 
+/// Number of guest audios uploaded from `client_ip` since `since`
+pub async fn count_guest_audios_from(
+    pool: &PgPool,
+    client_ip: &str,
+    since: DateTime<Utc>,
+) -> sqlx::Result<i64> {
+    let count: (i64,) = sqlx::query_as(
+        "select count(*) from audios
+         where claim_token is not null and client_ip = $1 and created_at >= $2",
+    )
+    .bind(client_ip)
+    .bind(since)
+    .fetch_one(pool)
+    .await?;
+    Ok(count.0)
+}
+
+/// The hashed claim token of a guest audio, if it can still be claimed
+pub async fn get_audio_claim_token(pool: &PgPool, audio_id: i32) -> sqlx::Result<Option<String>> {
+    let token: Option<(String,)> = sqlx::query_as(
+        "select claim_token from audios
+         where id = $1 and user_id is null and claim_expires_at > now()",
+    )
+    .bind(audio_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(token.map(|v| v.0))
+}
+
+/// Give a guest audio to `user_id`, returns false if it was already claimed
+pub async fn claim_audio(pool: &PgPool, audio_id: i32, user_id: i32) -> sqlx::Result<bool> {
+    let result = sqlx::query(
+        "update audios
+         set user_id = $2,
+             claim_token = null,
+             claim_expires_at = null,
+             updated_at = now()
+         where id = $1 and user_id is null and claim_expires_at > now()",
+    )
+    .bind(audio_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Delete the guest audios that were not claimed in time
+pub async fn delete_expired_guest_audios(pool: &PgPool) -> sqlx::Result<Vec<DbAudio>> {
+    sqlx::query_as(&format!(
+        "delete from audios
+         where user_id is null and claim_expires_at <= now()
+         returning {AUDIO_COLUMNS}"
+    ))
+    .fetch_all(pool)
+    .await
+}
+
 pub async fn insert_failed_audio_transcription(
     pool: &PgPool,
     audio_id: i32,
@@ -94,19 +568,229 @@ pub async fn insert_failed_audio_transcription(
     Ok(id.0)
 }
 
+/// Update the transcription and record it in its history, `source` is who
+/// wrote it, a speech to text provider or "human", and `provider` the speech
+/// to text provider that originally produced the text
 pub async fn update_audio_transcription(
     pool: &PgPool,
     audio_id: i32,
     new_transcription: &str,
+    source: &str,
+    provider: &str,
+    needs_review: bool,
 ) -> sqlx::Result<()> {
-    sqlx::query("update audios set transcription = $1 where id = $2")
-        .bind(new_transcription)
+    let word_count =
+        i32::try_from(new_transcription.split_whitespace().count()).unwrap_or(i32::MAX);
+    let char_count = i32::try_from(new_transcription.chars().count()).unwrap_or(i32::MAX);
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "update audios
+         set transcription = $1, transcription_status = $3, transcription_provider = $4,
+             needs_review = $5, transcription_word_count = $6, transcription_char_count = $7,
+             updated_at = now()
+         where id = $2",
+    )
+    .bind(new_transcription)
+    .bind(audio_id)
+    .bind(TRANSCRIPTION_COMPLETED)
+    .bind(provider)
+    .bind(needs_review)
+    .bind(word_count)
+    .bind(char_count)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "insert into transcription_versions(audio_id, transcription, source, provider)
+         values ($1, $2, $3, $4)",
+    )
+    .bind(audio_id)
+    .bind(new_transcription)
+    .bind(source)
+    .bind(provider)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+pub async fn get_transcription_version(
+    pool: &PgPool,
+    audio_id: i32,
+    version_id: i32,
+) -> sqlx::Result<Option<DbTranscriptionVersion>> {
+    sqlx::query_as(
+        "select id, audio_id, transcription, source, provider, created_at
+         from transcription_versions
+         where id = $1 and audio_id = $2",
+    )
+    .bind(version_id)
+    .bind(audio_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Every transcription the audio has had, newest first
+pub async fn get_transcription_versions(
+    pool: &PgPool,
+    audio_id: i32,
+) -> sqlx::Result<Vec<DbTranscriptionVersion>> {
+    sqlx::query_as(
+        "select id, audio_id, transcription, source, provider, created_at
+         from transcription_versions
+         where audio_id = $1
+         order by created_at desc, id desc",
+    )
+    .bind(audio_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Set the title of the audio unless it already has one
+pub async fn set_auto_title_if_empty(
+    pool: &PgPool,
+    audio_id: i32,
+    suggested_title: &str,
+) -> sqlx::Result<()> {
+    sqlx::query("update audios set title = $1, updated_at = now() where id = $2 and title is null")
+        .bind(suggested_title)
+        .bind(audio_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_audio_language(
+    pool: &PgPool,
+    audio_id: i32,
+    new_language: &str,
+) -> sqlx::Result<()> {
+    sqlx::query("update audios set language = $1, updated_at = now() where id = $2")
+        .bind(new_language)
+        .bind(audio_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_audio_detected_language(
+    pool: &PgPool,
+    audio_id: i32,
+    detected_language: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query("update audios set detected_language = $1, updated_at = now() where id = $2")
+        .bind(detected_language)
+        .bind(audio_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_audio_transcription_status(
+    pool: &PgPool,
+    audio_id: i32,
+    status: &str,
+) -> sqlx::Result<()> {
+    sqlx::query("update audios set transcription_status = $1, updated_at = now() where id = $2")
+        .bind(status)
+        .bind(audio_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Chunks of the audio already transcribed in `language` with chunks of
+/// `chunk_secs`, chunks made with other settings don't line up and are ignored
+pub async fn get_transcription_chunks(
+    pool: &PgPool,
+    audio_id: i32,
+    chunk_secs: i32,
+    language: &str,
+) -> sqlx::Result<Vec<DbTranscriptionChunk>> {
+    sqlx::query_as(
+        "select audio_id, chunk_index, transcription, confidence
+         from transcription_chunks
+         where audio_id = $1 and chunk_secs = $2 and language = $3
+         order by chunk_index",
+    )
+    .bind(audio_id)
+    .bind(chunk_secs)
+    .bind(language)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn upsert_transcription_chunk(
+    pool: &PgPool,
+    audio_id: i32,
+    chunk_index: i32,
+    chunk_secs: i32,
+    language: &str,
+    transcription: &str,
+    confidence: Option<f32>,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "insert into transcription_chunks
+            (audio_id, chunk_index, chunk_secs, language, transcription, confidence)
+         values ($1, $2, $3, $4, $5, $6)
+         on conflict (audio_id, chunk_index) do update
+            set chunk_secs = EXCLUDED.chunk_secs,
+                language = EXCLUDED.language,
+                transcription = EXCLUDED.transcription,
+                confidence = EXCLUDED.confidence,
+                created_at = now()",
+    )
+    .bind(audio_id)
+    .bind(chunk_index)
+    .bind(chunk_secs)
+    .bind(language)
+    .bind(transcription)
+    .bind(confidence)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_transcription_chunks(pool: &PgPool, audio_id: i32) -> sqlx::Result<()> {
+    sqlx::query("delete from transcription_chunks where audio_id = $1")
         .bind(audio_id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
+pub async fn clear_audio_transcription(pool: &PgPool, audio_id: i32) -> sqlx::Result<()> {
+    delete_transcription_chunks(pool, audio_id).await?;
+    sqlx::query(
+        "update audios
+         set transcription = null, transcription_status = $2, transcription_provider = null,
+             transcription_word_count = null, transcription_char_count = null,
+             updated_at = now()
+         where id = $1",
+    )
+    .bind(audio_id)
+    .bind(TRANSCRIPTION_PENDING)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn reset_failed_audio_transcription(
+    pool: &PgPool,
+    failed_audio_transcription_id: i32,
+    language: &str,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "update failed_audio_transcriptions
+         set retries = 0,
+             language = $2
+         where id = $1",
+    )
+    .bind(failed_audio_transcription_id)
+    .bind(language)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 pub async fn update_failed_audio_transcription(
     pool: &PgPool,
     failed_audio_transcription_id: i32,
@@ -123,13 +807,33 @@ pub async fn update_failed_audio_transcription(
     Ok(())
 }
 
-pub async fn delete_audio(pool: &PgPool, user_id: i32, audio_id: i32) -> sqlx::Result<bool> {
-    let result = sqlx::query("delete from audios where user_id = $1 and id = $2")
-        .bind(user_id)
-        .bind(audio_id)
-        .execute(pool)
-        .await?;
-    Ok(result.rows_affected() == 1)
+pub async fn reset_failed_audio_transcription_retries(
+    pool: &PgPool,
+    failed_audio_transcription_id: i32,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "update failed_audio_transcriptions
+         set retries = 0
+         where id = $1",
+    )
+    .bind(failed_audio_transcription_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_audio(
+    executor: impl PgExecutor<'_>,
+    user_id: i32,
+    audio_id: i32,
+) -> sqlx::Result<Option<DbAudio>> {
+    sqlx::query_as(&format!(
+        "delete from audios where user_id = $1 and id = $2 returning {AUDIO_COLUMNS}"
+    ))
+    .bind(user_id)
+    .bind(audio_id)
+    .fetch_optional(executor)
+    .await
 }
 
 pub async fn delete_failed_audio_transcription(
@@ -142,3 +846,23 @@ pub async fn delete_failed_audio_transcription(
         .await?;
     Ok(result.rows_affected() == 1)
 }
+
+pub async fn delete_failed_audio_transcriptions_of(
+    pool: &PgPool,
+    audio_id: i32,
+) -> sqlx::Result<()> {
+    sqlx::query("delete from failed_audio_transcriptions where audio_id = $1")
+        .bind(audio_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Delete the audios with `audio_ids`, regardless of who owns them
+pub async fn delete_audios(pool: &PgPool, audio_ids: &[i32]) -> sqlx::Result<u64> {
+    let result = sqlx::query("delete from audios where id = any($1)")
+        .bind(audio_ids)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
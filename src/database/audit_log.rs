@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+
+#[derive(FromRow)]
+pub struct DbRequestAuditLog {
+    pub id: i32,
+    pub request_id: Option<String>,
+    pub user_id: Option<i32>,
+    pub method: String,
+    pub path: String,
+    pub status_code: i32,
+    pub duration_ms: i64,
+    pub request_body_size: Option<i64>,
+    pub response_body_size: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct NewRequestAuditLog {
+    pub request_id: Option<String>,
+    pub user_id: Option<i32>,
+    pub method: String,
+    pub path: String,
+    pub status_code: i32,
+    pub duration_ms: i64,
+    pub request_body_size: Option<i64>,
+    pub response_body_size: Option<i64>,
+}
+
+pub async fn get_audit_log(pool: &PgPool, limit: i64) -> sqlx::Result<Vec<DbRequestAuditLog>> {
+    sqlx::query_as(
+        "select id, request_id, user_id, method, path, status_code, duration_ms,
+                request_body_size, response_body_size, created_at
+         from request_audit_log
+         order by id desc
+         limit $1",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_audit_log(pool: &PgPool, entry: &NewRequestAuditLog) -> sqlx::Result<()> {
+    sqlx::query(
+        "insert into request_audit_log
+            (request_id, user_id, method, path, status_code, duration_ms,
+             request_body_size, response_body_size)
+         values ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(&entry.request_id)
+    .bind(entry.user_id)
+    .bind(&entry.method)
+    .bind(&entry.path)
+    .bind(entry.status_code)
+    .bind(entry.duration_ms)
+    .bind(entry.request_body_size)
+    .bind(entry.response_body_size)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub const AUTH_LOGIN_SUCCEEDED: &str = "login_succeeded";
+pub const AUTH_LOGIN_FAILED: &str = "login_failed";
+pub const AUTH_PASSWORD_RESET_REQUESTED: &str = "password_reset_requested";
+pub const AUTH_PASSWORD_RESET_COMPLETED: &str = "password_reset_completed";
+/// A new token was issued to an already authenticated user
+pub const AUTH_TOKEN_REFRESHED: &str = "token_refreshed";
+pub const AUTH_READ_ONLY_TOKEN_ISSUED: &str = "read_only_token_issued";
+
+#[derive(FromRow)]
+pub struct DbAuthEvent {
+    pub id: i32,
+    pub event_type: String,
+    pub user_id: Option<i32>,
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct NewAuthEvent {
+    /// One of the `AUTH_*` events
+    pub event_type: &'static str,
+    pub user_id: Option<i32>,
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+}
+
+/// The latest auth events, only of `user_id` if given
+pub async fn get_auth_events(
+    pool: &PgPool,
+    user_id: Option<i32>,
+    limit: i64,
+) -> sqlx::Result<Vec<DbAuthEvent>> {
+    sqlx::query_as(
+        "select id, event_type, user_id, ip_address, user_agent, created_at
+         from auth_audit_log
+         where $1::int is null or user_id = $1
+         order by id desc
+         limit $2",
+    )
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_auth_event(pool: &PgPool, event: &NewAuthEvent) -> sqlx::Result<()> {
+    sqlx::query(
+        "insert into auth_audit_log (event_type, user_id, ip_address, user_agent)
+         values ($1, $2, $3, $4)",
+    )
+    .bind(event.event_type)
+    .bind(event.user_id)
+    .bind(&event.ip_address)
+    .bind(&event.user_agent)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
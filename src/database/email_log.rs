@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool};
+
+pub const EMAIL_SENT: &str = "sent";
+pub const EMAIL_FAILED: &str = "failed";
+
+#[derive(FromRow)]
+pub struct DbEmailLogEntry {
+    pub id: i32,
+    pub recipient: String,
+    pub subject: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The latest sent emails, only the ones to `recipient` if given
+pub async fn get_email_log(
+    pool: &PgPool,
+    recipient: Option<&str>,
+    limit: i64,
+) -> sqlx::Result<Vec<DbEmailLogEntry>> {
+    sqlx::query_as(
+        "select id, recipient, subject, status, error, created_at
+         from email_log
+         where $1::text is null or recipient = $1
+         order by id desc
+         limit $2",
+    )
+    .bind(recipient)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// `status` is one of [`EMAIL_SENT`] or [`EMAIL_FAILED`]
+pub async fn insert_email_log(
+    pool: &PgPool,
+    recipient: &str,
+    subject: &str,
+    status: &str,
+    error: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "insert into email_log (recipient, subject, status, error)
+         values ($1, $2, $3, $4)",
+    )
+    .bind(recipient)
+    .bind(subject)
+    .bind(status)
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
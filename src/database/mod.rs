@@ -1,9 +1,43 @@
+use futures::future::BoxFuture;
+use sqlx::{PgConnection, PgPool};
+
+mod audio_events;
 mod audios;
+mod audit_log;
+mod email_log;
+mod pending_uploads;
+mod tag_rules;
 mod tags;
 mod tokens;
 mod users;
 
+pub use audio_events::*;
 pub use audios::*;
+pub use audit_log::*;
+pub use email_log::*;
+pub use pending_uploads::*;
+pub use tag_rules::*;
 pub use tags::*;
 pub use tokens::*;
 pub use users::*;
+
+/// Run `f` inside a transaction, committed if it returns Ok and rolled back
+/// otherwise. The future can't borrow from its surroundings, move owned values
+/// into it instead.
+pub async fn transaction<T, E, F>(pool: &PgPool, f: F) -> Result<T, E>
+where
+    F: for<'c> FnOnce(&'c mut PgConnection) -> BoxFuture<'c, Result<T, E>>,
+    E: From<sqlx::Error>,
+{
+    let mut tx = pool.begin().await?;
+    match f(&mut tx).await {
+        Ok(value) => {
+            tx.commit().await?;
+            Ok(value)
+        }
+        Err(err) => {
+            tx.rollback().await?;
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+pub async fn insert_pending_upload(
+    pool: &PgPool,
+    storage_key: &str,
+    user_id: i32,
+    expires_at: DateTime<Utc>,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "insert into pending_uploads (storage_key, user_id, expires_at) values ($1, $2, $3)",
+    )
+    .bind(storage_key)
+    .bind(user_id)
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Whether a presigned upload to `storage_key` can still be confirmed
+pub async fn is_pending_upload(pool: &PgPool, storage_key: &str) -> sqlx::Result<bool> {
+    sqlx::query_scalar(
+        "select exists (select 1 from pending_uploads where storage_key = $1 and expires_at > now())",
+    )
+    .bind(storage_key)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn delete_pending_upload(pool: &PgPool, storage_key: &str) -> sqlx::Result<()> {
+    sqlx::query("delete from pending_uploads where storage_key = $1")
+        .bind(storage_key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Delete the presigned uploads that can no longer be confirmed, returns how
+/// many were deleted
+pub async fn delete_expired_pending_uploads(pool: &PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query("delete from pending_uploads where expires_at < now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
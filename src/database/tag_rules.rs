@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgConnection, PgPool};
+
+/// Audios whose transcription mentions `keyword` are tagged with `tag_name`
+#[derive(FromRow)]
+pub struct DbTagRule {
+    pub id: i32,
+    pub keyword: String,
+    pub tag_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn get_tag_rules(pool: &PgPool, user_id: i32) -> sqlx::Result<Vec<DbTagRule>> {
+    sqlx::query_as(
+        "select id, keyword, tag_name, created_at
+         from tag_rules
+         where user_id = $1
+         order by id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns None if the user already has `max_rules`. Takes a connection so the
+/// check and the insert can share a transaction, which holds a lock on the
+/// user until it ends.
+pub async fn insert_tag_rule(
+    conn: &mut PgConnection,
+    user_id: i32,
+    keyword: &str,
+    tag_name: &str,
+    max_rules: i64,
+) -> sqlx::Result<Option<DbTagRule>> {
+    sqlx::query("select id from users where id = $1 for update")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    let count: (i64,) = sqlx::query_as("select count(*) from tag_rules where user_id = $1")
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if count.0 >= max_rules {
+        return Ok(None);
+    }
+
+    sqlx::query_as(
+        "insert into tag_rules (user_id, keyword, tag_name)
+         values ($1, $2, $3)
+         returning id, keyword, tag_name, created_at",
+    )
+    .bind(user_id)
+    .bind(keyword)
+    .bind(tag_name)
+    .fetch_one(conn)
+    .await
+    .map(Some)
+}
+
+/// Returns false if the user has no rule with `rule_id`
+pub async fn delete_tag_rule(pool: &PgPool, user_id: i32, rule_id: i32) -> sqlx::Result<bool> {
+    let result = sqlx::query("delete from tag_rules where id = $1 and user_id = $2")
+        .bind(rule_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
@@ -1,7 +1,10 @@
 use std::collections::HashMap;
 
-use sqlx::{FromRow, PgPool};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
 
+use crate::models::default_tag_color;
+
+#[allow(dead_code)]
 #[derive(FromRow)]
 pub struct DbTag {
     pub id: i32,
@@ -10,23 +13,21 @@ pub struct DbTag {
     pub color: Option<String>,
 }
 
-pub async fn get_all_tags(pool: &PgPool, user_id: i32) -> sqlx::Result<Vec<DbTag>> {
-    sqlx::query_as("select id, user_id, name, color from tags where user_id = $1 order by id")
-        .bind(user_id)
-        .fetch_all(pool)
-        .await
-}
-
-pub async fn get_audio_tags(pool: &PgPool, audio_id: i32) -> sqlx::Result<Vec<DbTag>> {
+pub async fn get_all_tags(
+    pool: &PgPool,
+    user_id: i32,
+    limit: i64,
+    offset: i64,
+) -> sqlx::Result<Vec<DbTag>> {
     sqlx::query_as(
-        "select t.id, t.user_id, t.name, t.color
-            from tags t
-         join audio_tags a
-            on t.id = a.tag_id
-         where a.audio_id = $1
-         order by t.id",
+        "select id, user_id, name, color from tags
+         where user_id = $1
+         order by id
+         limit $2 offset $3",
     )
-    .bind(audio_id)
+    .bind(user_id)
+    .bind(limit)
+    .bind(offset)
     .fetch_all(pool)
     .await
 }
@@ -62,43 +63,82 @@ pub async fn get_audios_tags(
     Ok(tags)
 }
 
+pub async fn count_user_tags(executor: impl PgExecutor<'_>, user_id: i32) -> sqlx::Result<i64> {
+    let count: (i64,) = sqlx::query_as("select count(*) from tags where user_id = $1")
+        .bind(user_id)
+        .fetch_one(executor)
+        .await?;
+    Ok(count.0)
+}
+
+/// Get the tag named `tag_name`, creating it if it doesn't exist.
+///
+/// An explicit `tag_color` always overrides the stored color, otherwise the tag
+/// keeps its current color or gets one from the default palette. Returns None
+/// if the tag would have to be created but the user already has `max_tags`.
+/// Takes a connection so the checks and the insert can share a transaction,
+/// which holds a lock on the user until it ends.
 pub async fn get_or_create_tag(
-    pool: &PgPool,
+    conn: &mut PgConnection,
     user_id: i32,
     tag_name: &str,
     tag_color: Option<String>,
-) -> sqlx::Result<DbTag> {
-    let color_is_some = tag_color.is_some();
-    let query = format!(
-        "insert into tags (user_id, name{})
-         values ($1, $2{})
-         on conflict (user_id, name) do update
-            set name = EXCLUDED.name{}
-         returning id, user_id, name, color",
-        if color_is_some { ", color" } else { "" },
-        if color_is_some { ", $3" } else { "" },
-        if color_is_some {
-            ", color = EXCLUDED.color"
-        } else {
-            ""
-        }
-    );
-    let query = sqlx::query_as(&query).bind(user_id).bind(tag_name);
+    max_tags: i64,
+) -> sqlx::Result<Option<DbTag>> {
+    // Locks the user until the transaction ends, so concurrent requests can't
+    // both pass the limit check below
+    sqlx::query("select id from users where id = $1 for update")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
 
-    if let Some(color) = tag_color {
-        query.bind(color)
-    } else {
-        query
+    let exists: (bool,) =
+        sqlx::query_as("select exists(select 1 from tags where user_id = $1 and name = $2)")
+            .bind(user_id)
+            .bind(tag_name)
+            .fetch_one(&mut *conn)
+            .await?;
+    if !exists.0 && count_user_tags(&mut *conn, user_id).await? >= max_tags {
+        return Ok(None);
     }
-    .fetch_one(pool)
+
+    let override_color = tag_color.is_some();
+    let color = tag_color.unwrap_or_else(|| default_tag_color(tag_name).to_string());
+    sqlx::query_as(
+        "insert into tags (user_id, name, color)
+         values ($1, $2, $3)
+         on conflict (user_id, name) do update
+            set color = case
+                when $4 then EXCLUDED.color
+                else coalesce(tags.color, EXCLUDED.color)
+            end
+         returning id, user_id, name, color",
+    )
+    .bind(user_id)
+    .bind(tag_name)
+    .bind(color)
+    .bind(override_color)
+    .fetch_one(conn)
     .await
+    .map(Some)
 }
 
-pub async fn tag_audio(pool: &PgPool, tag_id: i32, audio_id: i32) -> sqlx::Result<()> {
-    sqlx::query("insert into audio_tags (tag_id, audio_id) values ($1, $2) on conflict (tag_id, audio_id) do nothing")
-        .bind(tag_id)
-        .bind(audio_id)
-        .execute(pool)
-        .await?;
+pub async fn tag_audio(
+    executor: impl PgExecutor<'_>,
+    tag_id: i32,
+    audio_id: i32,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "with tagged as (
+             insert into audio_tags (tag_id, audio_id) values ($1, $2)
+             on conflict (tag_id, audio_id) do nothing
+             returning audio_id
+         )
+         update audios set updated_at = now() where id in (select audio_id from tagged)",
+    )
+    .bind(tag_id)
+    .bind(audio_id)
+    .execute(executor)
+    .await?;
     Ok(())
 }
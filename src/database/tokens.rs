@@ -1,5 +1,5 @@
 use chrono::{DateTime, Duration, Utc};
-use sqlx::{FromRow, PgPool};
+use sqlx::{FromRow, PgExecutor, PgPool};
 
 #[derive(FromRow, Debug)]
 pub struct DbToken {
@@ -30,10 +30,10 @@ pub async fn insert_token(pool: &PgPool, user_id: i32, token: String) -> sqlx::R
     Ok(())
 }
 
-pub async fn delete_user_tokens(pool: &PgPool, user_id: i32) -> sqlx::Result<()> {
+pub async fn delete_user_tokens(executor: impl PgExecutor<'_>, user_id: i32) -> sqlx::Result<()> {
     sqlx::query("delete from password_reset_tokens where user_id = $1")
         .bind(user_id)
-        .execute(pool)
+        .execute(executor)
         .await?;
     Ok(())
 }
@@ -46,3 +46,12 @@ pub async fn delete_token(pool: &PgPool, user_id: i32, token: String) -> sqlx::R
         .await?;
     Ok(())
 }
+
+/// Delete the password reset tokens that already expired, returns how many
+/// were deleted
+pub async fn delete_expired_tokens(pool: &PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query("delete from password_reset_tokens where expires_at < now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
@@ -1,4 +1,4 @@
-use sqlx::{FromRow, PgPool};
+use sqlx::{FromRow, PgExecutor, PgPool};
 
 #[derive(FromRow)]
 pub struct DbUser {
@@ -6,31 +6,128 @@ pub struct DbUser {
     pub email: String,
     pub language: String,
     pub password: Option<String>,
+    pub avatar_url: Option<String>,
+    /// IANA name, like "America/New_York"
+    pub timezone: Option<String>,
 }
 
 pub async fn get_user(pool: &PgPool, id: i32) -> sqlx::Result<Option<DbUser>> {
-    sqlx::query_as("select id, email, language, password from users where id = $1")
-        .bind(id)
-        .fetch_optional(pool)
-        .await
+    sqlx::query_as(
+        "select id, email, language, password, avatar_url, timezone from users where id = $1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Emails are stored normalized, every write and lookup must go through this
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
 }
 
 pub async fn find_user_by_email(pool: &PgPool, email: &str) -> sqlx::Result<Option<DbUser>> {
-    sqlx::query_as("select id, email, language, password from users where email = $1")
-        .bind(email.to_lowercase())
+    sqlx::query_as(
+        "select id, email, language, password, avatar_url, timezone from users where email = $1",
+    )
+    .bind(normalize_email(email))
+    .fetch_optional(pool)
+    .await
+}
+
+/// Retries of audios without a user, like unclaimed guest audios
+pub const DEFAULT_MAX_TRANSCRIPTION_RETRIES: i16 = 3;
+
+/// How many times failed transcriptions of the user's audios are retried
+pub async fn get_user_max_retries(pool: &PgPool, user_id: i32) -> sqlx::Result<i16> {
+    let max_retries: Option<(i16,)> =
+        sqlx::query_as("select max_transcription_retries from users where id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+    Ok(max_retries
+        .map(|v| v.0)
+        .unwrap_or(DEFAULT_MAX_TRANSCRIPTION_RETRIES))
+}
+
+pub async fn is_user_admin(pool: &PgPool, id: i32) -> sqlx::Result<bool> {
+    let is_admin: Option<(bool,)> = sqlx::query_as("select is_admin from users where id = $1")
+        .bind(id)
         .fetch_optional(pool)
-        .await
+        .await?;
+    Ok(is_admin.map(|v| v.0).unwrap_or(false))
 }
 
 pub async fn update_user_password(
-    pool: &PgPool,
+    executor: impl PgExecutor<'_>,
     user_id: i32,
     new_password: String,
 ) -> sqlx::Result<()> {
     sqlx::query("update users set password = $1 where id = $2")
         .bind(new_password)
         .bind(user_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_user_language(
+    pool: &PgPool,
+    user_id: i32,
+    new_language: &str,
+) -> sqlx::Result<()> {
+    sqlx::query("update users set language = $1 where id = $2")
+        .bind(new_language)
+        .bind(user_id)
         .execute(pool)
         .await?;
     Ok(())
 }
+
+pub async fn update_user_timezone(
+    pool: &PgPool,
+    user_id: i32,
+    new_timezone: &str,
+) -> sqlx::Result<()> {
+    sqlx::query("update users set timezone = $1 where id = $2")
+        .bind(new_timezone)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_user_avatar(
+    pool: &PgPool,
+    user_id: i32,
+    avatar_url: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query("update users set avatar_url = $1 where id = $2")
+        .bind(avatar_url)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn create_user(
+    pool: &PgPool,
+    email: &str,
+    language: &str,
+    timezone: Option<&str>,
+    password: &str,
+    is_admin: bool,
+) -> sqlx::Result<i32> {
+    let id: (i32,) = sqlx::query_as(
+        "insert into users(email, language, timezone, password, is_admin)
+         values ($1, $2, $3, $4, $5)
+         returning id",
+    )
+    .bind(normalize_email(email))
+    .bind(language)
+    .bind(timezone)
+    .bind(password)
+    .bind(is_admin)
+    .fetch_one(pool)
+    .await?;
+    Ok(id.0)
+}
@@ -0,0 +1,249 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tempfile::TempDir;
+use tokio::{fs::File, process::Command};
+
+use crate::audio_storage::{stream_to_file, AudioStream};
+
+/// Content type of the audios produced by [`normalize`]
+pub const NORMALIZED_CONTENT_TYPE: &str = "audio/webm";
+
+/// Prefix of the files written by [`split`]
+const CHUNK_PREFIX: &str = "chunk";
+
+/// Convert `input` into `output` with ffmpeg, `options` are passed between both
+pub async fn convert(input: &Path, output: &Path, options: &[&str]) -> anyhow::Result<()> {
+    let exit_status = Command::new("ffmpeg")
+        .arg("-i")
+        .arg(input)
+        .args(options)
+        .arg(output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .context("failed executing ffmpeg")?;
+    if !exit_status.success() {
+        anyhow::bail!("ffmpeg exited with non-successful exit status: {exit_status}");
+    }
+    Ok(())
+}
+
+/// Create a temporary directory for audio processing inside `dir`
+pub async fn tempdir_in(dir: &Path) -> anyhow::Result<TempDir> {
+    let dir = PathBuf::from(dir);
+    tokio::task::spawn_blocking(move || TempDir::new_in(&dir))
+        .await?
+        .context("failed to create tmpdir")
+}
+
+/// Re-encode an audio as opus in a webm container, `extension` is the file
+/// extension of its current format
+pub async fn normalize(
+    stream: AudioStream,
+    extension: &str,
+    tmp_dir: &Path,
+) -> anyhow::Result<AudioStream> {
+    let tmpdir = tempdir_in(tmp_dir).await?;
+    let input = tmpdir.path().join(format!("input{extension}"));
+    let output = tmpdir.path().join("output.webm");
+
+    stream_to_file(&input, stream)
+        .await
+        .context("failed to write audio to normalize")?;
+    convert(&input, &output, &["-c:a", "libopus", "-b:a", "64k"]).await?;
+    let bytes = tokio::fs::read(&output)
+        .await
+        .context("failed to read normalized audio")?;
+
+    tokio::task::spawn_blocking(move || tmpdir.close())
+        .await?
+        .context("failed to delete tmpdir")?;
+
+    Ok(AudioStream::from_bytes(bytes.into()))
+}
+
+/// Write an audio to a temporary file to get its duration, then read it back
+/// from that file so it doesn't have to be received again. The duration is
+/// None if it can't be probed.
+pub async fn probe(
+    stream: AudioStream,
+    extension: &str,
+    tmp_dir: &Path,
+) -> anyhow::Result<(AudioStream, Option<Duration>)> {
+    let tmpdir = tempdir_in(tmp_dir).await?;
+    let input = tmpdir.path().join(format!("input{extension}"));
+    let length = stream_to_file(&input, stream)
+        .await
+        .context("failed to write audio to probe")?;
+
+    let duration = match file_duration(&input).await {
+        Ok(duration) => Some(duration),
+        Err(err) => {
+            tracing::warn!(?err, "failed to probe audio duration");
+            None
+        }
+    };
+
+    // The file can still be read once its directory is removed
+    let file = File::open(&input)
+        .await
+        .context("failed to open probed audio")?;
+    tokio::task::spawn_blocking(move || tmpdir.close())
+        .await?
+        .context("failed to delete tmpdir")?;
+
+    let stream = AudioStream::from_file(file).expected_length(Some(length));
+    Ok((stream, duration))
+}
+
+/// Get the duration of the audio at `input`
+pub async fn file_duration(input: &Path) -> anyhow::Result<Duration> {
+    match probe_duration(input).await? {
+        Some(duration) => Ok(duration),
+        None => decode_duration(input).await,
+    }
+}
+
+/// Split the audio at `input` into consecutive chunks of about
+/// `chunk_duration` without re-encoding, written to `output_dir` with the same
+/// `extension`. Returns the paths of the chunks in order.
+pub async fn split(
+    input: &Path,
+    output_dir: &Path,
+    extension: &str,
+    chunk_duration: Duration,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let pattern = output_dir.join(format!("{CHUNK_PREFIX}%05d{extension}"));
+    let segment_time = chunk_duration.as_secs().to_string();
+    convert(
+        input,
+        &pattern,
+        &[
+            "-f",
+            "segment",
+            "-segment_time",
+            &segment_time,
+            "-reset_timestamps",
+            "1",
+            "-c",
+            "copy",
+        ],
+    )
+    .await?;
+
+    let mut chunks = Vec::new();
+    let mut entries = tokio::fs::read_dir(output_dir)
+        .await
+        .context("failed to read the chunks directory")?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(CHUNK_PREFIX)
+        {
+            chunks.push(entry.path());
+        }
+    }
+    // The index is zero padded, so the names sort in order
+    chunks.sort();
+    Ok(chunks)
+}
+
+/// The duration in the container metadata, which the MediaRecorder API often
+/// leaves out of webm files
+async fn probe_duration(input: &Path) -> anyhow::Result<Option<Duration>> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(input)
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .context("failed executing ffprobe")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffprobe exited with non-successful exit status: {}",
+            output.status
+        );
+    }
+
+    Ok(parse_probe_duration(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parse the duration printed by ffprobe in seconds, like "12.345000". It
+/// prints "N/A" when the container doesn't have one
+fn parse_probe_duration(output: &str) -> Option<Duration> {
+    output
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+/// Decode the whole audio and read the duration ffmpeg reports at the end
+async fn decode_duration(input: &Path) -> anyhow::Result<Duration> {
+    let output = Command::new("ffmpeg")
+        .arg("-i")
+        .arg(input)
+        .args(["-f", "null", "-"])
+        .stdout(Stdio::null())
+        .output()
+        .await
+        .context("failed executing ffmpeg")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffmpeg exited with non-successful exit status: {}",
+            output.status
+        );
+    }
+
+    // The progress lines look like "size=N/A time=00:00:01.23 bitrate=N/A"
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let time = stderr
+        .rsplit("time=")
+        .next()
+        .and_then(|rest| rest.split_whitespace().next())
+        .context("ffmpeg did not report the duration")?;
+    parse_time(time).with_context(|| format!("invalid duration reported by ffmpeg: {time}"))
+}
+
+/// Parse a time like "00:01:02.50"
+fn parse_time(time: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    for part in time.split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_probe_duration() {
+        assert_eq!(
+            parse_probe_duration("12.345000\n"),
+            Some(Duration::from_millis(12345))
+        );
+        assert_eq!(parse_probe_duration("0.000000"), Some(Duration::ZERO));
+        assert_eq!(parse_probe_duration("N/A\n"), None);
+        assert_eq!(parse_probe_duration(""), None);
+        assert_eq!(parse_probe_duration("-1.5"), None);
+    }
+
+    #[test]
+    fn parses_time() {
+        assert_eq!(parse_time("00:00:01.50"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_time("01:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_time("N/A"), None);
+    }
+}
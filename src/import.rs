@@ -0,0 +1,234 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Client, Url,
+};
+
+/// Redirects followed before giving up on an import
+const MAX_REDIRECTS: usize = 10;
+
+/// Build the client used to download audios from urls given by users. It only
+/// connects to global unicast addresses, so imports can't reach the server's
+/// own network, and checks every redirect the same way
+pub fn http_client(connect_timeout: Duration, timeout: Duration) -> anyhow::Result<Client> {
+    client_allowing(is_global_unicast, connect_timeout, timeout)
+}
+
+fn client_allowing(
+    is_allowed: fn(IpAddr) -> bool,
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> anyhow::Result<Client> {
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if !is_url_allowed(attempt.url(), is_allowed) {
+            attempt.error("redirected to a non public address")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .redirect(policy)
+        // A proxy would resolve the host itself, skipping the checks
+        .no_proxy()
+        .dns_resolver(Arc::new(FilteringResolver { is_allowed }))
+        .build()
+        .context("failed to build import http client")
+}
+
+/// Whether the host of `url` may be fetched by an import. Domains are checked
+/// when they are resolved, so only ip hosts are rejected here
+pub fn is_public_url(url: &Url) -> bool {
+    is_url_allowed(url, is_global_unicast)
+}
+
+fn is_url_allowed(url: &Url, is_allowed: fn(IpAddr) -> bool) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_allowed(ip),
+        Err(_) => true,
+    }
+}
+
+/// Resolves hosts with the system resolver, dropping the addresses that are
+/// not allowed
+struct FilteringResolver {
+    is_allowed: fn(IpAddr) -> bool,
+}
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let is_allowed = self.is_allowed;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_allowed(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether `ip` is reachable on the public internet, `IpAddr::is_global` is
+/// not stable yet
+pub fn is_global_unicast(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_global_ipv4(ip),
+        IpAddr::V6(ip) => is_global_ipv6(ip),
+    }
+}
+
+fn is_global_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network"
+        || a == 0
+        // shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // reserved
+        || a >= 240)
+}
+
+fn is_global_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // NAT64 addresses reach the embedded ipv4 address
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_global_ipv4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // ipv4 compatible, deprecated
+        || segments[..6] == [0, 0, 0, 0, 0, 0]
+        // unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // link local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // site local, deprecated
+        || (segments[0] & 0xffc0) == 0xfec0
+        // documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn rejects_loopback_urls() {
+        assert!(!is_public_url(&url("http://127.0.0.1/audio.mp3")));
+        assert!(!is_public_url(&url("http://127.1.2.3:8080/audio.mp3")));
+        assert!(!is_public_url(&url("http://[::1]/audio.mp3")));
+        assert!(!is_public_url(&url("http://[::ffff:127.0.0.1]/audio.mp3")));
+    }
+
+    #[test]
+    fn rejects_private_urls() {
+        assert!(!is_public_url(&url("http://10.0.0.1/audio.mp3")));
+        assert!(!is_public_url(&url("http://192.168.1.10/audio.mp3")));
+        assert!(!is_public_url(&url("http://172.16.0.1/audio.mp3")));
+        assert!(!is_public_url(&url("http://169.254.169.254/latest")));
+        assert!(!is_public_url(&url("http://100.64.0.1/audio.mp3")));
+        assert!(!is_public_url(&url("http://0.0.0.0/audio.mp3")));
+        assert!(!is_public_url(&url("http://[fd00::1]/audio.mp3")));
+        assert!(!is_public_url(&url("http://[fe80::1]/audio.mp3")));
+        assert!(!is_public_url(&url("http://[64:ff9b::a00:1]/audio.mp3")));
+    }
+
+    #[test]
+    fn accepts_public_urls() {
+        assert!(is_public_url(&url("https://93.184.216.34/audio.mp3")));
+        assert!(is_public_url(&url("https://[2606:4700::1111]/audio.mp3")));
+        assert!(is_public_url(&url("https://example.com/audio.mp3")));
+    }
+
+    /// Serve a single redirect to `location` on a local port
+    async fn redirect_server(location: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        addr
+    }
+
+    fn test_client() -> Client {
+        // Loopback is allowed so the test server can be reached
+        client_allowing(
+            |ip| ip.is_loopback() || is_global_unicast(ip),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_redirects_to_private_addresses() {
+        let addr = redirect_server("http://10.0.0.1/audio.mp3").await;
+        let err = test_client()
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect(), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn rejects_domains_resolving_to_private_addresses() {
+        let client = client_allowing(
+            is_global_unicast,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let err = client
+            .get("http://localhost/audio.mp3")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_connect(), "{err:?}");
+    }
+}
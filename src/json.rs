@@ -0,0 +1,69 @@
+use std::error::Error;
+
+use axum::{
+    async_trait,
+    body::HttpBody,
+    extract::{rejection::JsonRejection, FromRequest},
+    http::Request,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{api_error::is_length_limit_error, ApiError, FieldError};
+
+/// Like [`axum::Json`], but a body that can't be extracted is rejected with an
+/// [`ApiError`], so clients get the same error format as everywhere else
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::Json::<T>::from_request(req, state).await {
+            Ok(axum::Json(value)) => Ok(Json(value)),
+            Err(rejection) => Err(rejection_error(rejection)),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+fn rejection_error(rejection: JsonRejection) -> ApiError {
+    // The source is the serde error, prefixed with the path of the field when
+    // the problem is in one, like `tags[1]: invalid type: integer, expected a string`
+    let reason = rejection
+        .source()
+        .map(ToString::to_string)
+        .unwrap_or_else(|| rejection.body_text());
+    match rejection {
+        JsonRejection::JsonDataError(_) => body_error(reason),
+        JsonRejection::JsonSyntaxError(_) => body_error(format!("is not valid JSON: {reason}")),
+        JsonRejection::MissingJsonContentType(_) => {
+            ApiError::UnsupportedMediaType(vec!["application/json".to_string()])
+        }
+        JsonRejection::BytesRejection(ref err) if is_length_limit_error(err) => {
+            ApiError::PayloadTooLarge
+        }
+        _ => ApiError::BadRequest,
+    }
+}
+
+fn body_error(message: String) -> ApiError {
+    ApiError::ValidationError(vec![FieldError {
+        field: "body",
+        message,
+    }])
+}
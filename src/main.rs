@@ -1,45 +1,89 @@
 mod api_error;
 mod audio_storage;
 mod claims;
+mod cli;
+mod client_info;
 mod database;
+mod ffmpeg;
+mod import;
+mod json;
+mod middleware;
 mod models;
 mod routes;
 mod stt;
+mod webhooks;
 
-use std::{sync::Arc, time::Duration};
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Semaphore;
 
-pub use api_error::{ApiError, Result};
-use audio_storage::AudioStorage;
+pub use api_error::{ApiError, FieldError, Result};
 use audio_storage::LocalAudioStorage;
-pub use claims::Claims;
-use stt::SpeechToText;
+use audio_storage::{audio_key, AudioStorage};
+pub use claims::{AdminClaims, Claims, Scope, WriteClaims};
+pub use client_info::ClientInfo;
+pub use json::Json;
 use stt::WhisperApi;
-use tower_http::{cors::CorsLayer, limit::RequestBodyLimitLayer, trace::TraceLayer};
+use stt::{
+    EmptyTranscription, RemoveFiller, SpeechToText, TranscriptionPostProcessor, TrimAndCapitalize,
+};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    cors::CorsLayer,
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 
 use anyhow::Context;
+use audio_storage::{StorageBackend, StorageKeyScheme};
 use axum::{
     http::{
-        header::{AUTHORIZATION, CONTENT_TYPE},
+        header::{AUTHORIZATION, CONTENT_TYPE, LINK},
         HeaderValue, Method,
     },
-    routing::{delete, get, post, put},
+    middleware::from_fn,
+    routing::{delete, get, patch, post, put},
     Extension, Router,
 };
+use clap::Parser;
+use cli::Cli;
 use jsonwebtoken::{DecodingKey, EncodingKey};
 use ring::rand::SystemRandom;
+use socket2::{Domain, Protocol, Socket, Type};
 use sqlx::PgPool;
 
-use routes::{audios::*, ping, users::*};
+use middleware::audit_log::audit_log;
+use middleware::maintenance::maintenance;
+use middleware::panic::{handle_panic, make_request_span};
+use routes::{admin::*, audios::*, health, ping, users::*};
 
 use crate::audio_storage::AzureAudioStorage;
 use crate::stt::PicovoiceLeopard;
 
 const MAX_BYTES_TO_SAVE: usize = 25 * 1_000_000;
+/// Largest block Azure accepts in a single Put Block call
+const MAX_AZURE_BLOCK_SIZE_BYTES: usize = 4000 * 1024 * 1024;
+const STT_INIT_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Database connections opened at startup, see [`warm_up_pool`]
+const POOL_WARM_UP_CONNECTIONS: u32 = 3;
+const MIN_RESET_TOKEN_BYTES: usize = 16;
+const RESET_LINK_TOKEN: &str = "{token}";
+const RESET_LINK_USER_ID: &str = "{user_id}";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
+    let cli = Cli::parse();
+
     tracing::info!("loading config");
     let config = Config::new().context("failed to load config")?;
 
@@ -54,95 +98,232 @@ async fn main() -> anyhow::Result<()> {
         .await
         .context("failed to run migrations")?;
 
-    let rand_rng = SystemRandom::new();
-    let secret = config.jwt_secret.as_bytes();
-    let keys = Keys {
-        encoding: EncodingKey::from_secret(secret),
-        decoding: DecodingKey::from_secret(secret),
-    };
+    warm_up_pool(&pool).await?;
 
-    let allowed_origin = config.allowed_origin.clone();
+    if let Some(command) = cli.command {
+        return cli::run(command, &config, &pool).await;
+    }
 
     tracing::info!("initializing storage");
-    let storage: Box<dyn AudioStorage + Send + Sync> =
-        if let Some(account) = &config.azure_storage_account {
-            tracing::info!("using azure audio storage");
-            let access_key = config.azure_storage_access_key.as_ref().unwrap();
-            let container = config.azure_storage_container.as_ref().unwrap();
-            Box::new(AzureAudioStorage::new(account, access_key, container))
-        } else {
-            tracing::info!("using local audio storage");
-            Box::new(LocalAudioStorage::new().await?)
-        };
+    let storage = init_storage(&config, config.storage_backend()).await?;
 
-    tracing::info!("initializing speech to text");
-    let stt: Box<dyn SpeechToText + Send + Sync> =
-        if let Some(ref openai_api_key) = config.openai_api_key {
-            tracing::info!("using openai");
-            Box::new(WhisperApi::new(openai_api_key.to_string()))
-        } else {
-            tracing::info!("using picovoice leopard");
-            let access_key = config.picovoice_access_key.clone().unwrap();
-            Box::new(
-                PicovoiceLeopard::new_with_languages(&["es"], access_key)
-                    .await
-                    .context("failed to get PicovoiceLeopard")?,
-            )
-        };
+    let listener = bind(&config)?;
+    if config.maintenance_mode {
+        tracing::warn!("starting in maintenance mode, writes are rejected");
+    }
+    let app_state = build_app_state(config, pool.clone(), None, storage)?;
+
+    let app_state2 = Arc::clone(&app_state);
+    let app_state3 = Arc::clone(&app_state);
+    let tokens_pool = pool.clone();
 
-    let app_state = Arc::new(AppStateInner {
-        pool: pool.clone(),
+    let app = build_router(app_state, pool);
+
+    tokio::spawn(async move {
+        if !init_transcriber_retrying(&app_state2).await {
+            return;
+        }
+        let available_at = Utc::now();
+        if let Err(err) = transcribe_old_failed(&app_state2).await {
+            tracing::error!(?err, "failed transcribing old failed");
+        }
+        if let Err(err) = transcribe_pending(&app_state2, available_at).await {
+            tracing::error!(?err, "failed transcribing pending");
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(err) = delete_expired_guest_audios(&app_state3).await {
+                tracing::error!(?err, "failed deleting expired guest audios");
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            match database::delete_expired_tokens(&tokens_pool).await {
+                Ok(deleted) => tracing::info!(deleted, "deleted expired password reset tokens"),
+                Err(err) => tracing::error!(?err, "failed deleting expired password reset tokens"),
+            }
+            match database::delete_expired_pending_uploads(&tokens_pool).await {
+                Ok(deleted) => tracing::info!(deleted, "deleted expired pending uploads"),
+                Err(err) => tracing::error!(?err, "failed deleting expired pending uploads"),
+            }
+        }
+    });
+
+    tracing::info!("listening on {}", listener.local_addr()?);
+    axum::Server::from_tcp(listener)?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+
+    Ok(())
+}
+
+/// The state shared by every handler. `transcriber` is None when speech to text
+/// is initialized later, see [`init_transcriber_retrying`]
+fn build_app_state(
+    config: Config,
+    pool: PgPool,
+    transcriber: Option<Transcriber>,
+    storage: Box<dyn AudioStorage + Send + Sync>,
+) -> anyhow::Result<AppState> {
+    let keys = Keys::from_secret(config.jwt_secret.as_bytes());
+    let http_client = stt::http_client(
+        Duration::from_secs(config.http_connect_timeout_secs),
+        Duration::from_secs(config.http_timeout_secs),
+    )?;
+    let import_client = import::http_client(
+        Duration::from_secs(config.http_connect_timeout_secs),
+        Duration::from_secs(config.http_timeout_secs),
+    )?;
+    let post_processors: Vec<Box<dyn TranscriptionPostProcessor + Send + Sync>> = vec![
+        Box::new(RemoveFiller::new(config.filler_words.clone())),
+        Box::new(TrimAndCapitalize),
+    ];
+    let maintenance_mode = AtomicBool::new(config.maintenance_mode);
+    Ok(Arc::new(AppStateInner {
+        pool,
         config,
-        rand_rng,
+        rand_rng: SystemRandom::new(),
         keys,
-        stt,
+        transcriber: transcriber.map(OnceCell::with_value).unwrap_or_default(),
         storage,
-    }) as AppState;
+        http_client,
+        import_client,
+        post_processors,
+        maintenance_mode,
+        transcribing: Mutex::default(),
+    }))
+}
 
-    let app_state2 = Arc::clone(&app_state);
+/// State for tests, with the required settings set to placeholders. The
+/// database is never connected to unless a query is made
+#[cfg(test)]
+fn test_app_state() -> AppState {
+    for (key, value) in [
+        ("DATABASE_URL", "postgres://localhost/audionotes"),
+        ("JWT_SECRET", "secret"),
+        ("ALLOWED_ORIGIN", "http://localhost:3000"),
+        ("SMTP_FROM", "audionotes@example.com"),
+        ("SMTP_USERNAME", "username"),
+        ("SMTP_PASSWORD", "password"),
+        ("SMTP_RELAY", "localhost"),
+        ("PASSWORD_RESET_LINK", "http://localhost:3000/reset"),
+    ] {
+        std::env::set_var(key, value);
+    }
+    let config = Config::new().unwrap();
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy(&config.database_url)
+        .unwrap();
+    build_app_state(
+        config,
+        pool,
+        None,
+        Box::new(audio_storage::MockAudioStorage),
+    )
+    .unwrap()
+}
+
+/// All the routes of the api with their middleware
+fn build_router(app_state: AppState, pool: PgPool) -> Router {
+    let allowed_origin = app_state.config.allowed_origin.clone();
+    let cors_allow_methods = app_state.config.cors_allow_methods.clone();
+    let cors_max_age_secs = app_state.config.cors_max_age_secs;
 
     let audio_routes = Router::new()
         .route("/", get(all_audios).post(new_audio))
+        .route("/import", post(import_audio))
+        .route("/presign-upload", post(presign_upload))
+        .route("/confirm-upload", post(confirm_upload))
+        .route("/retranscribe", post(bulk_retranscribe_audios))
+        .route("/guest", post(new_guest_audio))
+        .route("/:audio_id/claim", post(claim_audio))
         .route("/:audio_id", get(get_audio))
-        .route("/:audio_id/file", get(get_audio_file))
+        .route("/:audio_id/file", get(get_audio_file).head(head_audio_file))
+        .route("/:audio_id/presign-download", get(presign_download))
+        .route("/:audio_id/detected-language", get(get_detected_language))
+        .route("/:audio_id/events-log", get(get_audio_events_log))
         .route("/:audio_id", delete(delete_audio))
         .route("/:audio_id/tags", put(tag_audio))
-        .route("/tags", get(all_tags));
+        .route("/:audio_id/relanguage", post(relanguage_audio))
+        .route("/:audio_id/retranscribe", post(retranscribe_audio))
+        .route(
+            "/:audio_id/transcription/history",
+            get(get_transcription_history),
+        )
+        .route(
+            "/:audio_id/transcription/revert/:version_id",
+            post(revert_transcription),
+        )
+        .route("/tags", get(all_tags))
+        .route("/tag-rules", get(all_tag_rules).post(new_tag_rule))
+        .route("/tag-rules/:rule_id", delete(delete_tag_rule));
 
     let user_routes = Router::new()
         .route("/", get(get_user))
         .route("/authorize", post(authorize))
+        .route("/language", put(update_language))
+        .route("/avatar", patch(update_avatar))
+        .route("/read-only-token", post(read_only_token))
         .route("/reset-password", put(password_reset))
+        .route("/password/check", post(check_password))
         .route("/request-reset-password", put(request_password_reset));
 
+    let admin_routes = Router::new()
+        .route("/audit-log", get(get_audit_log))
+        .route("/auth-log", get(get_auth_log))
+        .route("/email-log", get(get_email_log))
+        .route("/storage/reconcile", post(reconcile_storage))
+        .route("/audios/:audio_id/storage-check", get(check_audio_storage))
+        .route(
+            "/failed-transcriptions/:failed_audio_transcription_id/retry",
+            post(retry_failed_transcription),
+        )
+        .route("/maintenance", put(set_maintenance_mode));
+
     let api_routes = Router::new()
         .route("/ping", get(ping))
+        .route("/health", get(health))
         .nest("/user", user_routes)
         .nest("/audios", audio_routes)
+        .nest("/admin", admin_routes)
+        .layer(from_fn(maintenance))
+        .layer(from_fn(audit_log))
         .layer(Extension(app_state))
         .layer(Extension(pool))
         .layer(RequestBodyLimitLayer::new(MAX_BYTES_TO_SAVE))
-        .layer(TraceLayer::new_for_http());
-
-    let app = Router::new().nest("/api", api_routes).layer(
-        CorsLayer::new()
-            .allow_origin(allowed_origin.parse::<HeaderValue>().unwrap())
-            .allow_headers([CONTENT_TYPE, AUTHORIZATION])
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE]),
-    );
-
-    tokio::spawn(async move {
-        if let Err(err) = transcribe_old_failed(&app_state2).await {
-            tracing::error!(?err, "failed transcribing old failed");
-        }
-    });
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
 
-    tracing::info!("listening on 8000");
-    axum::Server::bind(&"0.0.0.0:8000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await?;
+    let mut cors = CorsLayer::new()
+        .allow_origin(allowed_origin.parse::<HeaderValue>().unwrap())
+        .allow_headers([
+            CONTENT_TYPE,
+            AUTHORIZATION,
+            X_AUDIO_TITLE.clone(),
+            X_AUDIO_TAGS.clone(),
+            X_WHISPER_MODEL.clone(),
+        ])
+        .expose_headers([
+            routes::X_TOTAL_COUNT.clone(),
+            routes::X_PAGE_LIMIT.clone(),
+            LINK,
+        ])
+        .allow_methods(cors_allow_methods);
+    if cors_max_age_secs > 0 {
+        cors = cors.max_age(Duration::from_secs(cors_max_age_secs));
+    }
 
-    Ok(())
+    Router::new().nest("/api", api_routes).layer(cors)
 }
 
 pub type AppState = Arc<AppStateInner>;
@@ -152,8 +333,38 @@ pub struct AppStateInner {
     config: Config,
     rand_rng: SystemRandom,
     keys: Keys,
-    stt: Box<dyn SpeechToText + Send + Sync>,
+    /// Not set until speech to text is initialized, audios are stored but not
+    /// transcribed in the meantime
+    transcriber: OnceCell<Transcriber>,
     storage: Box<dyn AudioStorage + Send + Sync>,
+    http_client: reqwest::Client,
+    /// Only reaches public addresses, used for urls given by users
+    import_client: reqwest::Client,
+    /// Applied in order to every new transcription
+    post_processors: Vec<Box<dyn TranscriptionPostProcessor + Send + Sync>>,
+    /// Starts as `MAINTENANCE_MODE`, toggled by admins at runtime
+    maintenance_mode: AtomicBool,
+    /// Audios with a transcription in progress, see
+    /// [`routes::audios::claim_transcription`]
+    transcribing: Mutex<HashSet<i32>>,
+}
+
+impl AppStateInner {
+    /// Whether audios in `language` are allowed, and can be transcribed if
+    /// speech to text is available
+    fn supports_language(&self, language: &str) -> bool {
+        self.config.is_language_allowed(language)
+            && self
+                .transcriber
+                .get()
+                .is_none_or(|transcriber| transcriber.stt.supports_language(language))
+    }
+}
+
+pub struct Transcriber {
+    stt: Box<dyn SpeechToText + Send + Sync>,
+    /// Bounds how many transcriptions are sent to `stt` at the same time
+    permits: Semaphore,
 }
 
 impl std::fmt::Debug for AppStateInner {
@@ -163,55 +374,502 @@ impl std::fmt::Debug for AppStateInner {
 }
 
 pub struct Config {
+    /// Address to listen on, IPv4 or IPv6
+    host: IpAddr,
+    port: u16,
+    /// Also accept IPv4 connections when `host` is an IPv6 address
+    dual_stack: bool,
     database_url: String,
     jwt_secret: String,
     allowed_origin: String,
-    smtp_from: String,
+    cors_allow_methods: Vec<Method>,
+    /// How long browsers may cache preflight responses, 0 leaves it to them
+    cors_max_age_secs: u64,
+    /// Parsed from `SMTP_FROM`, with the display name of `SMTP_FROM_NAME` if set
+    smtp_from: lettre::message::Mailbox,
     smtp_username: String,
     smtp_password: String,
     smtp_relay: String,
+    smtp_port: u16,
+    /// Upgrade the connection with STARTTLS instead of connecting with TLS
+    smtp_starttls: bool,
+    /// Attempts to send an email before giving up, at least 1
+    smtp_send_attempts: u32,
+    /// Wait before the first retry of an email, doubled after every attempt
+    smtp_retry_backoff_ms: u64,
     password_reset_link: String,
+    reset_token_bytes: usize,
+    azure_storage_connection_string: Option<String>,
     azure_storage_account: Option<String>,
     azure_storage_access_key: Option<String>,
     azure_storage_container: Option<String>,
+    azure_block_size_bytes: usize,
+    storage_key_scheme: StorageKeyScheme,
+    /// Store local audios in subdirectories instead of all in `uploads/`
+    shard_local_storage: bool,
     openai_api_key: Option<String>,
+    openai_organization: Option<String>,
     picovoice_access_key: Option<String>,
+    whisper_concurrency: usize,
+    /// Model used by default with whisper
+    whisper_model: String,
+    /// Models that can be chosen per audio with `X-Whisper-Model`, always
+    /// contains `whisper_model`
+    whisper_models: Vec<String>,
+    /// Let whisper detect the language of audios, see [`WhisperApi`]
+    whisper_detect_language: bool,
+    picovoice_concurrency: usize,
+    /// Leopard instances kept for each language
+    leopard_instance_pool_size: usize,
+    http_connect_timeout_secs: u64,
+    http_timeout_secs: u64,
+    /// Languages users can sign up or switch to. Users keep a language removed
+    /// from the list until they change it, their tokens get the first one
+    allowed_languages: Vec<String>,
+    /// Pairs of language and word removed from transcriptions in that
+    /// language, like `en:um,en:uh`. See [`RemoveFiller`]
+    filler_words: Vec<(String, String)>,
+    trusted_proxies: Vec<IpAddr>,
+    password_min_length: usize,
+    allowed_audio_types: Vec<String>,
+    normalize_audio_on_upload: bool,
+    /// Check the magic bytes of uploads instead of trusting their `Content-Type`
+    sniff_audio_uploads: bool,
+    /// Redirect audio downloads to presigned storage urls when supported
+    redirect_audio_downloads: bool,
+    /// Start with writes rejected, see `middleware::maintenance`
+    maintenance_mode: bool,
+    tmp_dir: PathBuf,
+    min_audio_duration_ms: u64,
+    /// Longer audios are transcribed in chunks of this length, 0 disables it
+    transcription_chunk_secs: u64,
+    empty_transcriptions: EmptyTranscription,
+    /// Transcriptions with a lower confidence are flagged for review
+    review_confidence_threshold: f32,
+    max_tags_per_user: i64,
+    max_tag_rules_per_user: i64,
+    default_page_size: i64,
+    max_page_size: i64,
+    guest_uploads_enabled: bool,
+    guest_max_bytes: usize,
+    guest_uploads_per_hour: i64,
+    guest_audio_ttl_hours: i64,
 }
 
 impl Config {
     fn new() -> anyhow::Result<Config> {
-        let database_url = std::env::var("DATABASE_URL")?;
-        let jwt_secret = std::env::var("JWT_SECRET")?;
-        let allowed_origin = std::env::var("ALLOWED_ORIGIN")?;
-        let smtp_from = std::env::var("SMTP_FROM")?;
-        let smtp_username = std::env::var("SMTP_USERNAME")?;
-        let smtp_password = std::env::var("SMTP_PASSWORD")?;
-        let smtp_relay = std::env::var("SMTP_RELAY")?;
-        let password_reset_link = std::env::var("PASSWORD_RESET_LINK")?;
+        let host = parse_env_or("HOST", IpAddr::V4(Ipv4Addr::UNSPECIFIED))?;
+        let port = parse_env_or("PORT", 8000)?;
+        let dual_stack = parse_env_or("DUAL_STACK", false)?;
+        if dual_stack && host.is_ipv4() {
+            anyhow::bail!("DUAL_STACK requires HOST to be an IPv6 address, like ::");
+        }
+        let database_url = require_env("DATABASE_URL")?;
+        let jwt_secret = require_env("JWT_SECRET")?;
+        let allowed_origin = require_env("ALLOWED_ORIGIN")?;
+        let cors_allow_methods = std::env::var("CORS_ALLOW_METHODS")
+            .unwrap_or_else(|_| "GET,POST,PUT,PATCH,DELETE".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|method| !method.is_empty())
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .with_context(|| format!("invalid method in CORS_ALLOW_METHODS: {method}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let cors_max_age_secs = parse_env_or("CORS_MAX_AGE_SECS", 0)?;
+        let mut smtp_from: lettre::message::Mailbox = require_env("SMTP_FROM")?
+            .parse()
+            .context("SMTP_FROM must be a valid email address")?;
+        if let Ok(name) = std::env::var("SMTP_FROM_NAME") {
+            smtp_from.name = Some(name);
+        }
+        let smtp_username = require_env("SMTP_USERNAME")?;
+        let smtp_password = require_env("SMTP_PASSWORD")?;
+        let smtp_relay = require_env("SMTP_RELAY")?;
+        let smtp_port = parse_env_or("SMTP_PORT", 587)?;
+        let smtp_starttls = parse_env_or("SMTP_STARTTLS", true)?;
+        let smtp_send_attempts = parse_env_or("SMTP_SEND_ATTEMPTS", 3)?;
+        if smtp_send_attempts == 0 {
+            anyhow::bail!("SMTP_SEND_ATTEMPTS must be at least 1");
+        }
+        let smtp_retry_backoff_ms = parse_env_or("SMTP_RETRY_BACKOFF_MS", 1000)?;
+        let password_reset_link = password_reset_link(require_env("PASSWORD_RESET_LINK")?)?;
+        let reset_token_bytes = parse_env_or("RESET_TOKEN_BYTES", 48)?;
+        if reset_token_bytes < MIN_RESET_TOKEN_BYTES {
+            anyhow::bail!("RESET_TOKEN_BYTES must be at least {MIN_RESET_TOKEN_BYTES}");
+        }
 
         let azure_storage_account = std::env::var("AZURE_STORAGE_ACCOUNT").ok();
         let azure_storage_access_key = std::env::var("AZURE_STORAGE_ACCESS_KEY").ok();
         let azure_storage_container = std::env::var("AZURE_STORAGE_CONTAINER").ok();
+        let azure_storage_connection_string = std::env::var("AZURE_STORAGE_CONNECTION_STRING").ok();
+        if azure_storage_connection_string.is_some() {
+            if azure_storage_container.is_none() {
+                anyhow::bail!(
+                    "AZURE_STORAGE_CONTAINER required when AZURE_STORAGE_CONNECTION_STRING is set"
+                );
+            }
+        } else if azure_storage_account.is_some() {
+            if azure_storage_access_key.is_none() {
+                anyhow::bail!(
+                    "AZURE_STORAGE_ACCESS_KEY required when AZURE_STORAGE_ACCOUNT is set"
+                );
+            }
+            if azure_storage_container.is_none() {
+                anyhow::bail!("AZURE_STORAGE_CONTAINER required when AZURE_STORAGE_ACCOUNT is set");
+            }
+        }
+        let azure_block_size_bytes = parse_env_or("AZURE_BLOCK_SIZE_BYTES", 4 * 1024 * 1024)?;
+        if !(1..=MAX_AZURE_BLOCK_SIZE_BYTES).contains(&azure_block_size_bytes) {
+            anyhow::bail!(
+                "AZURE_BLOCK_SIZE_BYTES must be between 1 and {MAX_AZURE_BLOCK_SIZE_BYTES}"
+            );
+        }
+        let storage_key_scheme = match std::env::var("STORAGE_KEY_SCHEME").as_deref() {
+            Err(_) | Ok("id") => StorageKeyScheme::Id,
+            Ok("random") => StorageKeyScheme::Random,
+            Ok(scheme) => anyhow::bail!("unknown STORAGE_KEY_SCHEME {scheme}, use id or random"),
+        };
+
+        let shard_local_storage = parse_env_or("SHARD_LOCAL_STORAGE", false)?;
 
         let openai_api_key = std::env::var("OPENAI_API_KEY").ok();
+        let openai_organization = std::env::var("OPENAI_ORGANIZATION")
+            .ok()
+            .map(|organization| organization.trim().to_string())
+            .filter(|organization| !organization.is_empty());
+        if let Some(organization) = &openai_organization {
+            HeaderValue::from_str(organization)
+                .context("OPENAI_ORGANIZATION is not a valid header value")?;
+        }
         let picovoice_access_key = std::env::var("PICOVOICE_ACCESS_KEY").ok();
 
+        let whisper_concurrency = parse_env_or("WHISPER_CONCURRENCY", 4)?;
+        let whisper_model = parse_env_or("WHISPER_MODEL", "whisper-1".to_string())?;
+        let mut whisper_models: Vec<String> = std::env::var("WHISPER_MODELS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|model| model.trim().to_string())
+            .filter(|model| !model.is_empty())
+            .collect();
+        if !whisper_models.contains(&whisper_model) {
+            whisper_models.push(whisper_model.clone());
+        }
+        let whisper_detect_language = parse_env_or("WHISPER_DETECT_LANGUAGE", false)?;
+        let picovoice_concurrency = parse_env_or("PICOVOICE_CONCURRENCY", 1)?;
+        if whisper_concurrency == 0 || picovoice_concurrency == 0 {
+            anyhow::bail!("WHISPER_CONCURRENCY and PICOVOICE_CONCURRENCY must be at least 1");
+        }
+        let leopard_instance_pool_size = parse_env_or("LEOPARD_INSTANCE_POOL_SIZE", 1)?;
+        if leopard_instance_pool_size == 0 {
+            anyhow::bail!("LEOPARD_INSTANCE_POOL_SIZE must be at least 1");
+        }
+
+        let http_connect_timeout_secs = parse_env_or("HTTP_CONNECT_TIMEOUT_SECS", 10)?;
+        let http_timeout_secs = parse_env_or("HTTP_TIMEOUT_SECS", 300)?;
+
+        let allowed_languages = std::env::var("ALLOWED_LANGUAGES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|language| language.trim().to_lowercase())
+            .filter(|language| !language.is_empty())
+            .collect();
+
+        let filler_words = std::env::var("FILLER_WORDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once(':') {
+                Some((language, word)) if !language.is_empty() && !word.is_empty() => {
+                    Ok((language.trim().to_lowercase(), word.trim().to_lowercase()))
+                }
+                _ => anyhow::bail!("invalid FILLER_WORDS entry {entry}, expected language:word"),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| {
+                ip.parse()
+                    .with_context(|| format!("invalid ip in TRUSTED_PROXIES: {ip}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let password_min_length = parse_env_or("PASSWORD_MIN_LENGTH", 8)?;
+
+        let allowed_audio_types = std::env::var("ALLOWED_AUDIO_TYPES")
+            .unwrap_or_else(|_| "audio/webm".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|content_type| !content_type.is_empty())
+            .map(|content_type| {
+                audio_storage::media_type(content_type)
+                    .filter(|media_type| audio_storage::file_extension(media_type).is_some())
+                    .with_context(|| {
+                        format!("unsupported content type in ALLOWED_AUDIO_TYPES: {content_type}")
+                    })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let normalize_audio_on_upload = parse_env_or("NORMALIZE_AUDIO_ON_UPLOAD", false)?;
+        let sniff_audio_uploads = parse_env_or("SNIFF_AUDIO_UPLOADS", false)?;
+        let redirect_audio_downloads = parse_env_or("REDIRECT_AUDIO_DOWNLOADS", false)?;
+        let maintenance_mode = parse_env_or("MAINTENANCE_MODE", false)?;
+        let tmp_dir: PathBuf = parse_env_or("TMP_DIR", std::env::temp_dir())?;
+        if !tmp_dir.is_dir() {
+            anyhow::bail!("TMP_DIR {} is not a directory", tmp_dir.display());
+        }
+        let min_audio_duration_ms = parse_env_or("MIN_AUDIO_DURATION_MS", 0)?;
+        let transcription_chunk_secs = parse_env_or("TRANSCRIPTION_CHUNK_SECS", 0)?;
+        if i32::try_from(transcription_chunk_secs).is_err() {
+            anyhow::bail!("TRANSCRIPTION_CHUNK_SECS is too large");
+        }
+        let empty_transcriptions = match std::env::var("EMPTY_TRANSCRIPTIONS").as_deref() {
+            Err(_) | Ok("keep") => EmptyTranscription::Keep,
+            Ok("fail") => EmptyTranscription::Fail,
+            Ok("mark") => EmptyTranscription::Mark,
+            Ok(behavior) => {
+                anyhow::bail!("unknown EMPTY_TRANSCRIPTIONS {behavior}, use keep, fail or mark")
+            }
+        };
+        let review_confidence_threshold = parse_env_or("REVIEW_CONFIDENCE_THRESHOLD", 0.6)?;
+        if !(0.0..=1.0).contains(&review_confidence_threshold) {
+            anyhow::bail!("REVIEW_CONFIDENCE_THRESHOLD must be between 0 and 1");
+        }
+        let max_tags_per_user = parse_env_or("MAX_TAGS_PER_USER", 500)?;
+        let max_tag_rules_per_user = parse_env_or("MAX_TAG_RULES_PER_USER", 100)?;
+        let default_page_size = parse_env_or("DEFAULT_PAGE_SIZE", 100)?;
+        let max_page_size = parse_env_or("MAX_PAGE_SIZE", 1000)?;
+        if !(1..=max_page_size).contains(&default_page_size) {
+            anyhow::bail!("DEFAULT_PAGE_SIZE must be between 1 and MAX_PAGE_SIZE");
+        }
+
+        let guest_uploads_enabled = parse_env_or("GUEST_UPLOADS_ENABLED", false)?;
+        let guest_max_bytes = parse_env_or("GUEST_MAX_BYTES", 5 * 1_000_000)?;
+        let guest_uploads_per_hour = parse_env_or("GUEST_UPLOADS_PER_HOUR", 3)?;
+        let guest_audio_ttl_hours = parse_env_or("GUEST_AUDIO_TTL_HOURS", 24)?;
+
         Ok(Config {
+            host,
+            port,
+            dual_stack,
             database_url,
             jwt_secret,
             allowed_origin,
+            cors_allow_methods,
+            cors_max_age_secs,
             smtp_from,
             smtp_username,
             smtp_password,
             smtp_relay,
+            smtp_port,
+            smtp_starttls,
+            smtp_send_attempts,
+            smtp_retry_backoff_ms,
             password_reset_link,
+            reset_token_bytes,
+            azure_storage_connection_string,
             azure_storage_account,
             azure_storage_access_key,
             azure_storage_container,
+            azure_block_size_bytes,
+            storage_key_scheme,
+            shard_local_storage,
             openai_api_key,
+            openai_organization,
             picovoice_access_key,
+            whisper_concurrency,
+            whisper_model,
+            whisper_models,
+            whisper_detect_language,
+            picovoice_concurrency,
+            leopard_instance_pool_size,
+            http_connect_timeout_secs,
+            http_timeout_secs,
+            allowed_languages,
+            filler_words,
+            trusted_proxies,
+            password_min_length,
+            allowed_audio_types,
+            normalize_audio_on_upload,
+            sniff_audio_uploads,
+            redirect_audio_downloads,
+            maintenance_mode,
+            tmp_dir,
+            min_audio_duration_ms,
+            transcription_chunk_secs,
+            empty_transcriptions,
+            review_confidence_threshold,
+            max_tags_per_user,
+            max_tag_rules_per_user,
+            default_page_size,
+            max_page_size,
+            guest_uploads_enabled,
+            guest_max_bytes,
+            guest_uploads_per_hour,
+            guest_audio_ttl_hours,
         })
     }
+
+    /// The storage to use, azure when it is configured
+    fn storage_backend(&self) -> StorageBackend {
+        if self.azure_storage_connection_string.is_some() || self.azure_storage_account.is_some() {
+            StorageBackend::Azure
+        } else {
+            StorageBackend::Local
+        }
+    }
+
+    /// Whether `language` is a two letter code present in `ALLOWED_LANGUAGES`,
+    /// an empty allowlist allows every language
+    fn is_language_allowed(&self, language: &str) -> bool {
+        let is_code = language.len() == 2 && language.chars().all(|c| c.is_ascii_lowercase());
+        is_code
+            && (self.allowed_languages.is_empty()
+                || self
+                    .allowed_languages
+                    .iter()
+                    .any(|allowed| allowed == language))
+    }
+}
+
+/// Validate the template of the link sent to reset a password.
+///
+/// A link without placeholders is treated as a base url and the token and user
+/// id are appended as query parameters.
+fn password_reset_link(template: String) -> anyhow::Result<String> {
+    let placeholders = [RESET_LINK_TOKEN, RESET_LINK_USER_ID];
+    if !template.contains('{') {
+        return Ok(format!(
+            "{template}?token={RESET_LINK_TOKEN}&user_id={RESET_LINK_USER_ID}"
+        ));
+    }
+
+    for placeholder in placeholders {
+        if !template.contains(placeholder) {
+            anyhow::bail!("PASSWORD_RESET_LINK is missing the {placeholder} placeholder");
+        }
+    }
+    Ok(template)
+}
+
+fn require_env(key: &str) -> anyhow::Result<String> {
+    std::env::var(key)
+        .map_err(|_| anyhow::anyhow!("required environment variable {key} is not set"))
+}
+
+/// Open a few connections up front, so the first requests don't all wait for
+/// new connections at once
+async fn warm_up_pool(pool: &PgPool) -> anyhow::Result<()> {
+    let start = std::time::Instant::now();
+    let count = pool
+        .options()
+        .get_max_connections()
+        .min(POOL_WARM_UP_CONNECTIONS);
+    // Hold every connection until the end, otherwise the same one is reused
+    let mut connections = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        connections.push(
+            pool.acquire()
+                .await
+                .context("failed to warm up database pool")?,
+        );
+    }
+    drop(connections);
+    tracing::info!(connections = count, elapsed = ?start.elapsed(), "warmed up database pool");
+    Ok(())
+}
+
+/// Bind the address of the server, with `DUAL_STACK` the IPv6 socket is made
+/// to accept IPv4 connections too regardless of the system default
+fn bind(config: &Config) -> anyhow::Result<std::net::TcpListener> {
+    let address = SocketAddr::new(config.host, config.port);
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )
+    .context("failed to create socket")?;
+    if config.dual_stack {
+        socket
+            .set_only_v6(false)
+            .context("failed to enable dual stack")?;
+    }
+    socket
+        .set_reuse_address(true)
+        .context("failed to set SO_REUSEADDR")?;
+    socket
+        .bind(&address.into())
+        .with_context(|| format!("failed to bind {address}"))?;
+    socket.listen(1024).context("failed to listen")?;
+    socket
+        .set_nonblocking(true)
+        .context("failed to make socket non blocking")?;
+    Ok(socket.into())
+}
+
+/// Parse the environment variable `key`, or use `default` if it is not set
+fn parse_env_or<T>(key: &str, default: T) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("failed to parse environment variable {key}")),
+        Err(_) => Ok(default),
+    }
+}
+
+async fn init_storage(
+    config: &Config,
+    backend: StorageBackend,
+) -> anyhow::Result<Box<dyn AudioStorage + Send + Sync>> {
+    let storage: Box<dyn AudioStorage + Send + Sync> = match backend {
+        StorageBackend::Azure => {
+            tracing::info!("using azure audio storage");
+            let container = config
+                .azure_storage_container
+                .as_deref()
+                .context("azure audio storage is not configured")?;
+            if let Some(connection_string) = &config.azure_storage_connection_string {
+                Box::new(AzureAudioStorage::new_from_connection_string(
+                    connection_string,
+                    container,
+                    config.azure_block_size_bytes,
+                )?)
+            } else {
+                let (Some(account), Some(access_key)) = (
+                    &config.azure_storage_account,
+                    &config.azure_storage_access_key,
+                ) else {
+                    anyhow::bail!("azure audio storage is not configured");
+                };
+                Box::new(AzureAudioStorage::new(
+                    account,
+                    access_key,
+                    container,
+                    config.azure_block_size_bytes,
+                ))
+            }
+        }
+        StorageBackend::Local => {
+            tracing::info!("using local audio storage");
+            Box::new(LocalAudioStorage::new(config.shard_local_storage).await?)
+        }
+    };
+
+    storage
+        .health_check()
+        .await
+        .context("storage health check failed")?;
+    Ok(storage)
 }
 
 pub struct Keys {
@@ -219,18 +877,140 @@ pub struct Keys {
     decoding: DecodingKey,
 }
 
+impl Keys {
+    fn from_secret(secret: &[u8]) -> Keys {
+        Keys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+}
+
+/// Initialize speech to text, retrying until it succeeds. Returns false if no
+/// provider is configured
+async fn init_transcriber_retrying(state: &AppState) -> bool {
+    loop {
+        tracing::info!("initializing speech to text");
+        match init_transcriber(&state.config, &state.http_client).await {
+            Ok(Some(transcriber)) => {
+                let _ = state.transcriber.set(transcriber);
+                return true;
+            }
+            Ok(None) => {
+                tracing::warn!("no speech to text provider configured, transcription is disabled");
+                return false;
+            }
+            Err(err) => {
+                tracing::error!(
+                    ?err,
+                    "failed to initialize speech to text, retrying in {STT_INIT_RETRY_INTERVAL:?}"
+                );
+                tokio::time::sleep(STT_INIT_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn init_transcriber(
+    config: &Config,
+    http_client: &reqwest::Client,
+) -> anyhow::Result<Option<Transcriber>> {
+    let (stt, concurrency): (Box<dyn SpeechToText + Send + Sync>, _) =
+        if let Some(ref openai_api_key) = config.openai_api_key {
+            tracing::info!("using openai");
+            let whisper = WhisperApi::new(
+                openai_api_key.to_string(),
+                config.openai_organization.clone(),
+                http_client.clone(),
+                config.whisper_model.clone(),
+                config.whisper_detect_language,
+            );
+            if let Err(err) = whisper.check().await {
+                tracing::warn!(
+                    ?err,
+                    "failed to list openai models, check that OPENAI_API_KEY is valid"
+                );
+            }
+            (Box::new(whisper), config.whisper_concurrency)
+        } else if let Some(ref access_key) = config.picovoice_access_key {
+            tracing::info!("using picovoice leopard");
+            (
+                Box::new(
+                    PicovoiceLeopard::new_with_languages(
+                        &["es"],
+                        access_key.to_string(),
+                        http_client.clone(),
+                        config.tmp_dir.clone(),
+                        config.leopard_instance_pool_size,
+                    )
+                    .await
+                    .context(
+                        "failed to initialize PicovoiceLeopard: check that PICOVOICE_ACCESS_KEY \
+                         is valid and models are downloadable",
+                    )?,
+                ),
+                config.picovoice_concurrency,
+            )
+        } else {
+            return Ok(None);
+        };
+
+    Ok(Some(Transcriber {
+        stt,
+        permits: Semaphore::new(concurrency),
+    }))
+}
+
+/// Transcribe the audios that were stored while speech to text was unavailable
+async fn transcribe_pending(state: &AppState, created_before: DateTime<Utc>) -> anyhow::Result<()> {
+    let audios = database::get_pending_audios(&state.pool, created_before).await?;
+    if !audios.is_empty() {
+        tracing::info!("transcribing {} pending audios", audios.len());
+    }
+
+    for audio in audios {
+        if let Err(err) =
+            routes::audios::transcribe_and_update_retrying(state, audio.id, &audio.language, None)
+                .await
+        {
+            tracing::error!(?err, "failed to transcribe and update retrying");
+        }
+    }
+
+    Ok(())
+}
+
+async fn delete_expired_guest_audios(state: &AppState) -> anyhow::Result<()> {
+    let audios = database::delete_expired_guest_audios(&state.pool).await?;
+    if !audios.is_empty() {
+        tracing::info!("deleting {} expired guest audios", audios.len());
+    }
+
+    for audio in audios {
+        if let Err(err) = state.storage.delete(&audio_key(&audio)).await {
+            tracing::error!(
+                ?err,
+                audio_id = audio.id,
+                "failed to delete guest audio file"
+            );
+        }
+    }
+
+    Ok(())
+}
+
 async fn transcribe_old_failed(state: &AppState) -> anyhow::Result<()> {
     let failed_transcriptions = database::get_failed_audio_transcriptions(&state.pool).await?;
 
     let ids = failed_transcriptions
         .iter()
-        .filter(|i| i.retries < 3)
+        .filter(|i| i.retries < i32::from(i.max_retries))
         .map(|i| (i.id, i.audio_id))
         .collect::<Vec<_>>();
 
     let ids_excluded = failed_transcriptions
         .iter()
-        .filter(|i| i.retries >= 3)
+        .filter(|i| i.retries >= i32::from(i.max_retries))
         .map(|i| (i.id, i.audio_id))
         .collect::<Vec<_>>();
 
@@ -249,7 +1029,7 @@ async fn transcribe_old_failed(state: &AppState) -> anyhow::Result<()> {
     }
 
     for failed_transcription in failed_transcriptions {
-        if failed_transcription.retries >= 3 {
+        if failed_transcription.retries >= i32::from(failed_transcription.max_retries) {
             continue;
         }
 
@@ -0,0 +1,77 @@
+use std::time::Instant;
+
+use axum::{
+    body::HttpBody,
+    http::{header::CONTENT_LENGTH, Method, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+
+use crate::{
+    database::{self, NewRequestAuditLog},
+    AppState, Claims,
+};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Record every mutating request in the `request_audit_log` table
+pub async fn audit_log<B>(
+    Extension(state): Extension<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !is_mutating(request.method()) {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string);
+    let user_id = Claims::from_headers(headers, &state.keys).map(|claims| claims.user_id);
+    let request_body_size = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let duration_ms = start.elapsed().as_millis().try_into().unwrap_or(i64::MAX);
+
+    let response_body_size = response
+        .body()
+        .size_hint()
+        .exact()
+        .and_then(|size| size.try_into().ok());
+
+    let entry = NewRequestAuditLog {
+        request_id,
+        user_id,
+        method,
+        path,
+        status_code: response.status().as_u16().into(),
+        duration_ms,
+        request_body_size,
+        response_body_size,
+    };
+
+    // Don't make the client wait for the audit log
+    tokio::spawn(async move {
+        if let Err(err) = database::insert_audit_log(&state.pool, &entry).await {
+            tracing::error!(?err, "failed to insert audit log");
+        }
+    });
+
+    response
+}
+
+pub(crate) fn is_mutating(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::DELETE | Method::PATCH
+    )
+}
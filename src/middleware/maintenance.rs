@@ -0,0 +1,35 @@
+use std::sync::atomic::Ordering;
+
+use axum::{
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+
+use super::audit_log::is_mutating;
+use crate::{api_error::ApiError, AppState};
+
+/// Writes that are still allowed in maintenance mode: logging in and checking
+/// passwords don't change any data, and admins need to be able to turn
+/// maintenance mode off
+const ALLOWED_PATHS: &[&str] = &["/user/authorize", "/user/password/check"];
+const ALLOWED_PREFIX: &str = "/admin/";
+
+/// Reject mutating requests with 503 while maintenance mode is on, reads keep
+/// working
+pub async fn maintenance<B>(
+    Extension(state): Extension<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path();
+    if state.maintenance_mode.load(Ordering::Relaxed)
+        && is_mutating(request.method())
+        && !ALLOWED_PATHS.contains(&path)
+        && !path.starts_with(ALLOWED_PREFIX)
+    {
+        return ApiError::MaintenanceMode.into_response();
+    }
+    next.run(request).await
+}
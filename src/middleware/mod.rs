@@ -0,0 +1,3 @@
+pub mod audit_log;
+pub mod maintenance;
+pub mod panic;
@@ -0,0 +1,40 @@
+use std::any::Any;
+
+use axum::{
+    body::Body,
+    http::Request,
+    response::{IntoResponse, Response},
+};
+use tracing::Span;
+
+use super::audit_log::REQUEST_ID_HEADER;
+use crate::api_error::ApiError;
+
+/// Span of every request, so events logged while handling it (including
+/// panics caught by [`handle_panic`]) carry its path and request id
+pub fn make_request_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        request_id,
+    )
+}
+
+/// Log a panic of a handler and respond with a regular internal server error
+pub fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let panic = if let Some(msg) = err.downcast_ref::<String>() {
+        msg.as_str()
+    } else if let Some(msg) = err.downcast_ref::<&str>() {
+        msg
+    } else {
+        "unknown panic"
+    };
+    tracing::error!(panic, "handler panicked");
+    ApiError::InternalServerError.into_response()
+}
@@ -5,27 +5,273 @@ use serde::Serialize;
 pub struct User {
     pub email: String,
     pub language: String,
+    pub avatar_url: Option<String>,
+    pub timezone: Option<String>,
+    pub scope: crate::Scope,
 }
 
 #[derive(Serialize)]
 pub struct Audio {
     pub id: i32,
+    pub title: Option<String>,
     pub transcription: Option<String>,
+    pub transcription_status: String,
+    pub provider: Option<String>,
+    /// Model chosen at upload, None for the default of the provider
+    pub model: Option<String>,
+    pub needs_review: bool,
+    pub word_count: Option<i32>,
+    pub char_count: Option<i32>,
+    /// Always serialized as RFC 3339 in UTC, see [`rfc3339`]
+    #[serde(serialize_with = "rfc3339::serialize")]
     pub created_at: DateTime<Utc>,
+    /// `created_at` in the user's timezone, as RFC 3339 with its offset
+    pub created_at_local: Option<String>,
+    #[serde(serialize_with = "rfc3339::serialize")]
+    pub updated_at: DateTime<Utc>,
+    pub language: String,
+    pub detected_language: Option<String>,
+    pub user_agent: Option<String>,
+    pub client_ip: Option<String>,
     pub tags: Vec<Tag>,
 }
 
+impl Audio {
+    /// `timezone` is the IANA name of the user's timezone, if they set one
+    pub fn new(audio: crate::database::DbAudio, tags: Vec<Tag>, timezone: Option<&str>) -> Self {
+        let created_at_local = timezone
+            .and_then(|timezone| timezone.parse::<chrono_tz::Tz>().ok())
+            .map(|timezone| rfc3339::format_local(&audio.created_at, timezone));
+        Self {
+            id: audio.id,
+            title: audio.title,
+            transcription: audio.transcription,
+            transcription_status: audio.transcription_status,
+            provider: audio.transcription_provider,
+            model: audio.transcription_model,
+            needs_review: audio.needs_review,
+            word_count: audio.transcription_word_count,
+            char_count: audio.transcription_char_count,
+            created_at: audio.created_at,
+            created_at_local,
+            updated_at: audio.updated_at,
+            language: audio.language,
+            detected_language: audio.detected_language,
+            user_agent: audio.user_agent,
+            client_ip: audio.client_ip,
+            tags,
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct Tag {
     pub name: String,
     pub color: Option<String>,
+    /// `color` as red, green and blue components
+    pub rgb: Option<[u8; 3]>,
+}
+
+/// Colors assigned to tags created without an explicit color.
+pub const TAG_COLOR_PALETTE: [&str; 12] = [
+    "#ef4444", "#f97316", "#f59e0b", "#84cc16", "#22c55e", "#14b8a6", "#06b6d4", "#3b82f6",
+    "#6366f1", "#a855f7", "#ec4899", "#64748b",
+];
+
+/// Pick a color from [`TAG_COLOR_PALETTE`] based on the tag name, so the same
+/// name always gets the same color.
+pub fn default_tag_color(name: &str) -> &'static str {
+    // FNV-1a, stable across platforms and compiler versions
+    let hash = name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    TAG_COLOR_PALETTE[hash as usize % TAG_COLOR_PALETTE.len()]
+}
+
+/// Whether `color` is a hex color in the `#rrggbb` form.
+pub fn is_valid_tag_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The red, green and blue components of a `#rrggbb` color.
+pub fn parse_color_to_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+    if !is_valid_tag_color(hex) {
+        return None;
+    }
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((component(1)?, component(3)?, component(5)?))
 }
 
 impl From<crate::database::DbTag> for Tag {
     fn from(db_tag: crate::database::DbTag) -> Self {
+        let rgb = db_tag
+            .color
+            .as_deref()
+            .and_then(parse_color_to_rgb)
+            .map(|(r, g, b)| [r, g, b]);
         Self {
             name: db_tag.name,
             color: db_tag.color,
+            rgb,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TagRule {
+    pub id: i32,
+    pub keyword: String,
+    pub tag_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<crate::database::DbTagRule> for TagRule {
+    fn from(db_rule: crate::database::DbTagRule) -> Self {
+        Self {
+            id: db_rule.id,
+            keyword: db_rule.keyword,
+            tag_name: db_rule.tag_name,
+            created_at: db_rule.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditLogEntry {
+    pub id: i32,
+    pub request_id: Option<String>,
+    pub user_id: Option<i32>,
+    pub method: String,
+    pub path: String,
+    pub status_code: i32,
+    pub duration_ms: i64,
+    pub request_body_size: Option<i64>,
+    pub response_body_size: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<crate::database::DbRequestAuditLog> for AuditLogEntry {
+    fn from(db_entry: crate::database::DbRequestAuditLog) -> Self {
+        Self {
+            id: db_entry.id,
+            request_id: db_entry.request_id,
+            user_id: db_entry.user_id,
+            method: db_entry.method,
+            path: db_entry.path,
+            status_code: db_entry.status_code,
+            duration_ms: db_entry.duration_ms,
+            request_body_size: db_entry.request_body_size,
+            response_body_size: db_entry.response_body_size,
+            created_at: db_entry.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuthEvent {
+    pub id: i32,
+    pub event_type: String,
+    pub user_id: Option<i32>,
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<crate::database::DbAuthEvent> for AuthEvent {
+    fn from(db_event: crate::database::DbAuthEvent) -> Self {
+        Self {
+            id: db_event.id,
+            event_type: db_event.event_type,
+            user_id: db_event.user_id,
+            ip_address: db_event.ip_address,
+            user_agent: db_event.user_agent,
+            created_at: db_event.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AudioEvent {
+    pub id: i32,
+    pub event_type: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<crate::database::DbAudioEvent> for AudioEvent {
+    fn from(db_event: crate::database::DbAudioEvent) -> Self {
+        Self {
+            id: db_event.id,
+            event_type: db_event.event_type,
+            error: db_event.error,
+            created_at: db_event.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EmailLogEntry {
+    pub id: i32,
+    pub recipient: String,
+    pub subject: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<crate::database::DbEmailLogEntry> for EmailLogEntry {
+    fn from(db_entry: crate::database::DbEmailLogEntry) -> Self {
+        Self {
+            id: db_entry.id,
+            recipient: db_entry.recipient,
+            subject: db_entry.subject,
+            status: db_entry.status,
+            error: db_entry.error,
+            created_at: db_entry.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TranscriptionVersion {
+    pub id: i32,
+    pub transcription: String,
+    pub source: String,
+    pub provider: Option<String>,
+    #[serde(serialize_with = "rfc3339::serialize")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<crate::database::DbTranscriptionVersion> for TranscriptionVersion {
+    fn from(db_version: crate::database::DbTranscriptionVersion) -> Self {
+        Self {
+            id: db_version.id,
+            transcription: db_version.transcription,
+            source: db_version.source,
+            provider: db_version.provider,
+            created_at: db_version.created_at,
         }
     }
 }
+
+/// Timestamps sent to clients, formatted as RFC 3339 in UTC with millisecond
+/// precision and a `Z` suffix (e.g. `2024-01-05T12:00:00.000Z`). Filters that
+/// take timestamps accept this same format.
+pub mod rfc3339 {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use chrono_tz::Tz;
+    use serde::Serializer;
+
+    pub fn format_local(value: &DateTime<Utc>, timezone: Tz) -> String {
+        value
+            .with_timezone(&timezone)
+            .to_rfc3339_opts(SecondsFormat::Millis, false)
+    }
+
+    pub fn serialize<S: Serializer>(
+        value: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+}
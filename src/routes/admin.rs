@@ -0,0 +1,291 @@
+use std::{collections::HashSet, sync::atomic::Ordering};
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query},
+    Extension,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio_storage::audio_key,
+    database,
+    models::{AuditLogEntry, AuthEvent, EmailLogEntry},
+    routes::audios::{attempt_transcription, claim_transcription},
+    AdminClaims, ApiError, AppState, Json,
+};
+
+const DEFAULT_AUDIT_LOG_LIMIT: i64 = 100;
+const MAX_AUDIT_LOG_LIMIT: i64 = 1000;
+
+/// Audios newer than this might still be uploading, so missing files are expected
+const RECONCILE_GRACE_PERIOD_MINUTES: i64 = 60;
+
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    limit: Option<i64>,
+}
+
+pub async fn get_audit_log(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Query(query): Query<AuditLogQuery>,
+) -> crate::Result<Json<Vec<AuditLogEntry>>> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LOG_LIMIT)
+        .clamp(1, MAX_AUDIT_LOG_LIMIT);
+    let entries = database::get_audit_log(&state.pool, limit)
+        .await?
+        .into_iter()
+        .map(AuditLogEntry::from)
+        .collect();
+    Ok(Json(entries))
+}
+
+#[derive(Deserialize)]
+pub struct AuthLogQuery {
+    user_id: Option<i32>,
+    limit: Option<i64>,
+}
+
+pub async fn get_auth_log(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Query(query): Query<AuthLogQuery>,
+) -> crate::Result<Json<Vec<AuthEvent>>> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LOG_LIMIT)
+        .clamp(1, MAX_AUDIT_LOG_LIMIT);
+    let events = database::get_auth_events(&state.pool, query.user_id, limit)
+        .await?
+        .into_iter()
+        .map(AuthEvent::from)
+        .collect();
+    Ok(Json(events))
+}
+
+#[derive(Deserialize)]
+pub struct EmailLogQuery {
+    recipient: Option<String>,
+    limit: Option<i64>,
+}
+
+pub async fn get_email_log(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Query(query): Query<EmailLogQuery>,
+) -> crate::Result<Json<Vec<EmailLogEntry>>> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LOG_LIMIT)
+        .clamp(1, MAX_AUDIT_LOG_LIMIT);
+    let entries = database::get_email_log(&state.pool, query.recipient.as_deref(), limit)
+        .await?
+        .into_iter()
+        .map(EmailLogEntry::from)
+        .collect();
+    Ok(Json(entries))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MaintenanceModePayload {
+    enabled: bool,
+}
+
+/// Turn maintenance mode on or off until the next restart
+pub async fn set_maintenance_mode(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Json(payload): Json<MaintenanceModePayload>,
+) -> Json<MaintenanceModePayload> {
+    state
+        .maintenance_mode
+        .store(payload.enabled, Ordering::Relaxed);
+    tracing::warn!(enabled = payload.enabled, "maintenance mode changed");
+    Json(payload)
+}
+
+#[derive(Deserialize)]
+pub struct ReconcileStorageQuery {
+    #[serde(default)]
+    delete: bool,
+}
+
+#[derive(Serialize)]
+pub struct ReconcileStorageBody {
+    /// Stored files without an audio
+    orphaned_files: Vec<String>,
+    /// Audios without a stored file
+    missing_files: Vec<i32>,
+    deleted: bool,
+}
+
+/// Compare the stored files against the audios table, with `?delete=true`
+/// the orphans in either direction are deleted, otherwise they're only reported
+pub async fn reconcile_storage(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Query(query): Query<ReconcileStorageQuery>,
+) -> crate::Result<Json<ReconcileStorageBody>> {
+    let stored_keys = state
+        .storage
+        .list()
+        .await
+        .context("failed to list stored audios")?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let created_before = Utc::now() - Duration::minutes(RECONCILE_GRACE_PERIOD_MINUTES);
+    let audios = database::get_all_audios(&state.pool, created_before).await?;
+    let audio_keys = audios.iter().map(audio_key).collect::<HashSet<_>>();
+
+    let missing_files = audios
+        .iter()
+        .filter(|audio| !stored_keys.contains(&audio_key(audio)))
+        .map(|audio| audio.id)
+        .collect::<Vec<_>>();
+
+    // Files of audios in the grace period are not in `audio_keys`, look them up
+    let mut orphaned_files = Vec::new();
+    for key in stored_keys.difference(&audio_keys) {
+        if !is_key_of_existing_audio(&state, key).await? {
+            orphaned_files.push(key.clone());
+        }
+    }
+    orphaned_files.sort();
+
+    tracing::info!(
+        orphaned_files = orphaned_files.len(),
+        missing_files = missing_files.len(),
+        "reconciled storage"
+    );
+
+    if query.delete {
+        for key in &orphaned_files {
+            state
+                .storage
+                .delete(key)
+                .await
+                .with_context(|| format!("failed to delete orphaned file {key}"))?;
+        }
+        database::delete_audios(&state.pool, &missing_files).await?;
+    }
+
+    Ok(Json(ReconcileStorageBody {
+        orphaned_files,
+        missing_files,
+        deleted: query.delete,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct StorageCheckBody {
+    audio_id: i32,
+    key: String,
+    exists: bool,
+}
+
+/// Check that the stored file of a single audio is there
+pub async fn check_audio_storage(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Path(audio_id): Path<i32>,
+) -> crate::Result<Json<StorageCheckBody>> {
+    let audio = database::get_audio(&state.pool, audio_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let key = audio_key(&audio);
+    let exists = state
+        .storage
+        .exists(&key)
+        .await
+        .with_context(|| format!("failed to check if {key} exists"))?;
+    if !exists {
+        tracing::warn!(audio_id, key, "audio has no stored file");
+    }
+    Ok(Json(StorageCheckBody {
+        audio_id,
+        key,
+        exists,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct RetryFailedTranscriptionBody {
+    audio_id: i32,
+    transcription_status: String,
+    /// Why the last retry failed
+    error: Option<String>,
+}
+
+/// Retry a single failed transcription once, from scratch. A failure counts as
+/// the first retry, the failed transcriptions sweep retries it again later
+pub async fn retry_failed_transcription(
+    Extension(state): Extension<AppState>,
+    _admin: AdminClaims,
+    Path(failed_audio_transcription_id): Path<i32>,
+) -> crate::Result<Json<RetryFailedTranscriptionBody>> {
+    let failed_transcription =
+        database::get_failed_audio_transcription(&state.pool, failed_audio_transcription_id)
+            .await?
+            .ok_or(ApiError::NotFound)?;
+    if state.transcriber.get().is_none() {
+        return Err(ApiError::TranscriptionUnavailable);
+    }
+    let _claim = claim_transcription(&state, failed_transcription.audio_id)
+        .ok_or(ApiError::Conflict("audio is already being transcribed"))?;
+    database::reset_failed_audio_transcription_retries(&state.pool, failed_transcription.id)
+        .await?;
+
+    tracing::info!(
+        failed_audio_transcription_id,
+        audio_id = failed_transcription.audio_id,
+        "retrying failed transcription"
+    );
+    let error = attempt_transcription(
+        &state,
+        failed_transcription.audio_id,
+        &failed_transcription.language,
+        Some(failed_transcription.id),
+    )
+    .await?
+    .err()
+    .map(|(_, err)| format!("{err:#}"));
+
+    let audio = database::get_audio(&state.pool, failed_transcription.audio_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(RetryFailedTranscriptionBody {
+        audio_id: audio.id,
+        transcription_status: audio.transcription_status,
+        error,
+    }))
+}
+
+/// Whether `key` is the file of an audio or of an upload that can still be
+/// confirmed
+async fn is_key_of_existing_audio(state: &AppState, key: &str) -> crate::Result<bool> {
+    if database::get_audio_by_storage_key(&state.pool, key)
+        .await?
+        .is_some()
+    {
+        return Ok(true);
+    }
+    // Presigned uploads have no audio until they are confirmed
+    if database::is_pending_upload(&state.pool, key).await? {
+        return Ok(true);
+    }
+
+    let audio_id = key
+        .split_once('.')
+        .and_then(|(audio_id, _extension)| audio_id.parse().ok());
+    match audio_id {
+        Some(audio_id) => Ok(database::get_audio(&state.pool, audio_id)
+            .await?
+            .is_some_and(|audio| audio_key(&audio) == key)),
+        None => Ok(false),
+    }
+}
@@ -1,74 +1,300 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Context;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
     body::StreamBody,
-    extract::{BodyStream, Path},
-    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
-    Extension, Json,
+    extract::{BodyStream, OriginalUri, Path, Query},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Extension,
 };
-use futures::{future::BoxFuture, FutureExt};
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use jsonwebtoken::{decode, encode, Header, Validation};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use tokio::fs::File;
+use tokio_util::bytes::Bytes;
 use tracing::{instrument, Instrument};
 
 use crate::{
-    audio_storage::AudioStream,
-    database,
-    models::{Audio, Tag},
-    ApiError, AppState, Claims,
+    audio_storage::{
+        audio_key, file_extension, media_type, random_storage_key, sniff_content_type, storage_key,
+        stream_to_file, AudioStream, PresignAction, StorageError, StorageKeyScheme, SNIFF_LENGTH,
+    },
+    database::{self, DbAudio, DbTranscriptionChunk, NewAudio},
+    ffmpeg,
+    models::{is_valid_tag_color, Audio, AudioEvent, Tag, TagRule, TranscriptionVersion},
+    routes::{
+        pagination_headers,
+        users::{generate_token, hash},
+        Pagination,
+    },
+    stt::{EmptyTranscription, Transcription, TranscriptionError},
+    ApiError, AppState, Claims, ClientInfo, FieldError, Json, Transcriber, WriteClaims,
 };
 
-pub const AUDIO_FILE_MIMETYPE: &str = "audio/webm";
+const TITLE_MAX_WORDS: usize = 10;
+const TITLE_MAX_CHARS: usize = 60;
 
+/// Longest title that fits in the database column
+const TITLE_MAX_LENGTH: usize = 255;
+
+/// Length of `audios.content_type`, which keeps the parameters of uploads
+const CONTENT_TYPE_MAX_LENGTH: usize = 255;
+
+pub static X_AUDIO_TITLE: HeaderName = HeaderName::from_static("x-audio-title");
+pub static X_AUDIO_TAGS: HeaderName = HeaderName::from_static("x-audio-tags");
+/// Whisper model to transcribe an audio with, one of `WHISPER_MODELS`
+pub static X_WHISPER_MODEL: HeaderName = HeaderName::from_static("x-whisper-model");
+
+/// How long presigned urls and upload tokens are valid
+const PRESIGN_EXPIRATION_MINUTES: i64 = 15;
+
+/// Audience of upload tokens, so they are never mistaken for other tokens
+const UPLOAD_TOKEN_AUDIENCE: &str = "upload";
+
+/// How long `new_audio` waits for the transcription when called with `?sync=true`
+const SYNC_TRANSCRIPTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[instrument(skip_all, fields(audio_id = audio_id))]
 pub async fn get_audio(
     Extension(pool): Extension<PgPool>,
     claims: Claims,
     Path(audio_id): Path<i32>,
 ) -> crate::Result<Json<Audio>> {
-    let audio = database::get_audio_by(&pool, audio_id, claims.user_id).await?;
-    let audio_tags = database::get_audio_tags(&pool, audio_id)
+    let (audio, tags) = database::get_audio_with_tags_by(&pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let tags = tags.into_iter().map(Tag::from).collect();
+    Ok(Json(Audio::new(audio, tags, claims.timezone.as_deref())))
+}
+
+#[derive(Serialize)]
+pub struct DetectedLanguageBody {
+    language: String,
+    detected_language: Option<String>,
+    /// Whether the detected language differs from the language of the audio,
+    /// None if no language was detected
+    mismatch: Option<bool>,
+}
+
+/// The language the speech to text provider detected in an audio, to warn
+/// users when the language they chose might be wrong
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn get_detected_language(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Path(audio_id): Path<i32>,
+) -> crate::Result<Json<DetectedLanguageBody>> {
+    let audio = database::get_audio_by(&pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let mismatch = audio
+        .detected_language
+        .as_ref()
+        .map(|detected| *detected != audio.language);
+    Ok(Json(DetectedLanguageBody {
+        language: audio.language,
+        detected_language: audio.detected_language,
+        mismatch,
+    }))
+}
+
+/// The processing timeline of an audio, oldest event first
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn get_audio_events_log(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Path(audio_id): Path<i32>,
+) -> crate::Result<Json<Vec<AudioEvent>>> {
+    database::get_audio_by(&pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let events = database::get_audio_events(&pool, audio_id)
         .await?
         .into_iter()
-        .map(Tag::from)
+        .map(AudioEvent::from)
         .collect();
-    match audio {
-        Some(audio) if audio.user_id == claims.user_id => Ok(Json(Audio {
-            id: audio.id,
-            transcription: audio.transcription,
-            created_at: audio.created_at,
-            tags: audio_tags,
-        })),
-        None | Some(_) => Err(ApiError::NotFound),
-    }
+    Ok(Json(events))
 }
 
+/// Stream the file of an audio. With `REDIRECT_AUDIO_DOWNLOADS` backends that
+/// support presigned urls redirect to the storage instead.
+#[instrument(skip_all, fields(audio_id = audio_id))]
 pub async fn get_audio_file(
     Extension(state): Extension<AppState>,
     claims: Claims,
     Path(audio_id): Path<i32>,
-) -> crate::Result<StreamBody<AudioStream>> {
+    headers: HeaderMap,
+) -> crate::Result<Response> {
     let audio = match database::get_audio_by(&state.pool, audio_id, claims.user_id).await? {
         Some(audio) => audio,
         None => return Err(ApiError::NotFound),
     };
 
-    if audio.user_id != claims.user_id {
+    if audio.user_id != Some(claims.user_id) {
         return Err(ApiError::NotFound);
     }
 
-    let stream = state.storage.get(audio.id).await?;
+    let key = audio_key(&audio);
+    if state.config.redirect_audio_downloads {
+        let expires_at = Utc::now() + chrono::Duration::minutes(PRESIGN_EXPIRATION_MINUTES);
+        match state
+            .storage
+            .presign(&key, PresignAction::Download, expires_at)
+            .await
+        {
+            Ok(url) => return Ok((StatusCode::FOUND, [(LOCATION, url)]).into_response()),
+            Err(StorageError::Unsupported) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let file_headers = audio_file_headers(&state, &audio, &key).await?;
+    if is_not_modified(&headers, &file_headers) {
+        return Ok((StatusCode::NOT_MODIFIED, file_headers).into_response());
+    }
+
+    let stream = state.storage.get(&key).await?;
     let body = StreamBody::new(stream);
 
-    Ok(body)
+    Ok((file_headers, body).into_response())
+}
+
+/// The headers `get_audio_file` would respond with, to check the size of an
+/// audio or whether a cached copy is still valid without downloading it
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn head_audio_file(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(audio_id): Path<i32>,
+    headers: HeaderMap,
+) -> crate::Result<Response> {
+    let audio = database::get_audio_by(&state.pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let file_headers = audio_file_headers(&state, &audio, &audio_key(&audio)).await?;
+    if is_not_modified(&headers, &file_headers) {
+        return Ok((StatusCode::NOT_MODIFIED, file_headers).into_response());
+    }
+    Ok(file_headers.into_response())
+}
+
+/// Content type, length and etag of the stored file of an audio. Files don't
+/// change once stored, so the etag only depends on the audio.
+async fn audio_file_headers(
+    state: &AppState,
+    audio: &DbAudio,
+    key: &str,
+) -> crate::Result<HeaderMap> {
+    let size = state.storage.size(key).await?;
+    let etag = format!("\"{}-{}\"", audio.id, audio.created_at.timestamp_millis());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(&audio.content_type).context("invalid audio content type")?,
+    );
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+    headers.insert(ETAG, HeaderValue::from_str(&etag).context("invalid etag")?);
+    Ok(headers)
+}
+
+/// Whether the `If-None-Match` of a request matches the etag of the file
+fn is_not_modified(request_headers: &HeaderMap, file_headers: &HeaderMap) -> bool {
+    let (Some(if_none_match), Some(etag)) =
+        (request_headers.get(IF_NONE_MATCH), file_headers.get(ETAG))
+    else {
+        return false;
+    };
+    if_none_match.to_str().is_ok_and(|if_none_match| {
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/").as_bytes() == etag.as_bytes())
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AllAudiosQuery {
+    /// Words that must appear in the transcription
+    q: Option<String>,
+    tag: Option<String>,
+    needs_review: Option<bool>,
+    /// Only audios whose transcription has at least this many words
+    min_words: Option<i32>,
+    /// Only audios created at or after this RFC 3339 timestamp or date
+    #[serde(alias = "created_from")]
+    from: Option<String>,
+    /// Only audios created before this RFC 3339 timestamp or date
+    #[serde(alias = "created_to")]
+    to: Option<String>,
+    /// Only audios changed after this RFC 3339 timestamp or date
+    modified_since: Option<String>,
+    /// `summary` to get only the beginning of each transcription, `full` (the
+    /// default) for the whole transcriptions
+    fields: Option<String>,
 }
 
+/// Parse an RFC 3339 timestamp, or a date (`2024-01-05`) as its midnight in UTC
+fn parse_time_filter(field: &'static str, value: &str) -> crate::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        .map_err(|_| field_error(field, "must be an RFC 3339 timestamp or a date"))
+}
+
+#[instrument(skip_all)]
 pub async fn all_audios(
-    Extension(pool): Extension<PgPool>,
+    Extension(state): Extension<AppState>,
     claims: Claims,
-) -> crate::Result<(StatusCode, Json<Vec<Audio>>)> {
-    let audios = database::get_audios_by(&pool, claims.user_id).await?;
-    let mut audios_tags = database::get_audios_tags(&pool, claims.user_id).await?;
+    OriginalUri(uri): OriginalUri,
+    Query(query): Query<AllAudiosQuery>,
+    Query(pagination): Query<Pagination>,
+) -> crate::Result<(StatusCode, HeaderMap, Json<Vec<Audio>>)> {
+    let pool = &state.pool;
+    let limit = pagination.limit(&state.config);
+    let filter = database::AudioFilter {
+        text_query: query.q.filter(|q| !q.trim().is_empty()),
+        tag: query.tag,
+        needs_review: query.needs_review,
+        min_words: query.min_words,
+        from: query
+            .from
+            .map(|from| parse_time_filter("from", &from))
+            .transpose()?,
+        to: query
+            .to
+            .map(|to| parse_time_filter("to", &to))
+            .transpose()?,
+        modified_since: query
+            .modified_since
+            .map(|modified_since| parse_time_filter("modified_since", &modified_since))
+            .transpose()?,
+    };
+    let snippets = match query.fields.as_deref() {
+        None | Some("full") => false,
+        Some("summary") => true,
+        Some(_) => return Err(field_error("fields", "must be summary or full")),
+    };
+    let audios = database::get_audios_by(
+        pool,
+        claims.user_id,
+        &filter,
+        snippets,
+        limit,
+        pagination.offset(),
+    )
+    .await?;
+    let total = database::count_audios_by(pool, claims.user_id, &filter).await?;
+    let mut audios_tags = database::get_audios_tags(pool, claims.user_id).await?;
     let audios = audios
         .into_iter()
         .map(|audio| {
@@ -78,15 +304,14 @@ pub async fn all_audios(
                 .into_iter()
                 .map(Tag::from)
                 .collect();
-            Audio {
-                id: audio.id,
-                transcription: audio.transcription,
-                created_at: audio.created_at,
-                tags,
-            }
+            Audio::new(audio, tags, claims.timezone.as_deref())
         })
         .collect();
-    Ok((StatusCode::OK, Json(audios)))
+    Ok((
+        StatusCode::OK,
+        pagination_headers(&uri, total, limit, pagination.offset()),
+        Json(audios),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -95,152 +320,1851 @@ pub struct TagAudioPayload {
     color: Option<String>,
 }
 
+#[instrument(skip_all, fields(audio_id = audio_id))]
 pub async fn tag_audio(
-    Extension(pool): Extension<PgPool>,
+    Extension(state): Extension<AppState>,
     Path(audio_id): Path<i32>,
-    claims: Claims,
+    WriteClaims(claims): WriteClaims,
     Json(payload): Json<TagAudioPayload>,
-) -> crate::Result<StatusCode> {
-    let audio = database::get_audio_by(&pool, audio_id, claims.user_id).await?;
+) -> crate::Result<(StatusCode, Json<Tag>)> {
+    if let Some(color) = &payload.color {
+        if !is_valid_tag_color(color) {
+            return Err(ApiError::BadRequest);
+        }
+    }
+    if !is_valid_tag_name(&payload.name) {
+        return Err(field_error(
+            "name",
+            &format!("must be between 1 and {TAG_NAME_MAX_LENGTH} characters"),
+        ));
+    }
+    let pool = &state.pool;
+    let audio = database::get_audio_by(pool, audio_id, claims.user_id).await?;
     match audio {
-        Some(a) if a.user_id == claims.user_id => {}
+        Some(a) if a.user_id == Some(claims.user_id) => {}
         _ => return Err(ApiError::NotFound),
     }
-    let db_tag =
-        database::get_or_create_tag(&pool, claims.user_id, &payload.name, payload.color).await?;
-    database::tag_audio(&pool, db_tag.id, audio_id).await?;
-    Ok(StatusCode::OK)
+    let user_id = claims.user_id;
+    let max_tags = state.config.max_tags_per_user;
+    let db_tag = database::transaction(pool, move |conn| {
+        async move {
+            let db_tag =
+                database::get_or_create_tag(conn, user_id, &payload.name, payload.color, max_tags)
+                    .await?
+                    .ok_or(ApiError::LimitExceeded("maximum number of tags reached"))?;
+            database::tag_audio(conn, db_tag.id, audio_id).await?;
+            Ok::<_, ApiError>(db_tag)
+        }
+        .boxed()
+    })
+    .await?;
+    Ok((StatusCode::OK, Json(Tag::from(db_tag))))
 }
 
-pub async fn all_tags(
+const TAG_RULE_KEYWORD_MAX_LENGTH: usize = 64;
+const TAG_NAME_MAX_LENGTH: usize = 25;
+
+/// The rules the user's audios are tagged with automatically once transcribed
+#[instrument(skip_all)]
+pub async fn all_tag_rules(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+) -> crate::Result<Json<Vec<TagRule>>> {
+    let rules = database::get_tag_rules(&pool, claims.user_id)
+        .await?
+        .into_iter()
+        .map(TagRule::from)
+        .collect();
+    Ok(Json(rules))
+}
+
+#[derive(Deserialize)]
+pub struct NewTagRulePayload {
+    /// Word or phrase the transcription has to contain, ignoring case
+    keyword: String,
+    tag_name: String,
+}
+
+#[instrument(skip_all)]
+pub async fn new_tag_rule(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    Json(payload): Json<NewTagRulePayload>,
+) -> crate::Result<(StatusCode, Json<TagRule>)> {
+    let keyword = keyword_words(&payload.keyword).join(" ");
+    if keyword.is_empty() {
+        return Err(field_error("keyword", "must contain a word"));
+    }
+    if keyword.chars().count() > TAG_RULE_KEYWORD_MAX_LENGTH {
+        return Err(field_error(
+            "keyword",
+            &format!("must be at most {TAG_RULE_KEYWORD_MAX_LENGTH} characters"),
+        ));
+    }
+    let tag_name = payload.tag_name.trim();
+    if tag_name.is_empty() || tag_name.chars().count() > TAG_NAME_MAX_LENGTH {
+        return Err(field_error(
+            "tag_name",
+            &format!("must be between 1 and {TAG_NAME_MAX_LENGTH} characters"),
+        ));
+    }
+
+    let user_id = claims.user_id;
+    let tag_name = tag_name.to_string();
+    let max_rules = state.config.max_tag_rules_per_user;
+    let rule = database::transaction(&state.pool, move |conn| {
+        async move {
+            database::insert_tag_rule(conn, user_id, &keyword, &tag_name, max_rules)
+                .await?
+                .ok_or(ApiError::LimitExceeded(
+                    "maximum number of tag rules reached",
+                ))
+        }
+        .boxed()
+    })
+    .await?;
+    Ok((StatusCode::CREATED, Json(TagRule::from(rule))))
+}
+
+#[instrument(skip_all)]
+pub async fn delete_tag_rule(
     Extension(pool): Extension<PgPool>,
+    WriteClaims(claims): WriteClaims,
+    Path(rule_id): Path<i32>,
+) -> crate::Result<StatusCode> {
+    if !database::delete_tag_rule(&pool, claims.user_id, rule_id).await? {
+        return Err(ApiError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The lowercase words of `text`, anything that isn't a letter or a digit
+/// separates words
+fn keyword_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whether `keyword` appears in `words` as whole, consecutive words
+fn contains_keyword(words: &[String], keyword: &str) -> bool {
+    let keyword = keyword_words(keyword);
+    !keyword.is_empty() && words.windows(keyword.len()).any(|window| window == keyword)
+}
+
+/// Tag the audio with the tags of the rules whose keyword is in its
+/// transcription as whole words
+async fn apply_tag_rules(
+    state: &AppState,
+    audio_id: i32,
+    user_id: i32,
+    transcription: &str,
+) -> anyhow::Result<()> {
+    let rules = database::get_tag_rules(&state.pool, user_id).await?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    let words = keyword_words(transcription);
+    let mut tag_names = Vec::new();
+    for rule in rules {
+        if contains_keyword(&words, &rule.keyword) && !tag_names.contains(&rule.tag_name) {
+            tag_names.push(rule.tag_name);
+        }
+    }
+
+    let max_tags = state.config.max_tags_per_user;
+    database::transaction(&state.pool, move |conn| {
+        async move {
+            for name in &tag_names {
+                match database::get_or_create_tag(conn, user_id, name, None, max_tags).await? {
+                    Some(db_tag) => database::tag_audio(&mut *conn, db_tag.id, audio_id).await?,
+                    None => {
+                        tracing::warn!(audio_id, tag = name, "maximum number of tags reached")
+                    }
+                }
+            }
+            Ok::<_, sqlx::Error>(())
+        }
+        .boxed()
+    })
+    .await?;
+    Ok(())
+}
+
+/// List the tags of the user, the total number of tags is sent in the
+/// `X-Total-Count` header
+#[instrument(skip_all)]
+pub async fn all_tags(
+    Extension(state): Extension<AppState>,
     claims: Claims,
-) -> crate::Result<(StatusCode, Json<Vec<Tag>>)> {
-    let tags = database::get_all_tags(&pool, claims.user_id)
+    OriginalUri(uri): OriginalUri,
+    Query(pagination): Query<Pagination>,
+) -> crate::Result<(StatusCode, HeaderMap, Json<Vec<Tag>>)> {
+    let limit = pagination.limit(&state.config);
+    let tags = database::get_all_tags(&state.pool, claims.user_id, limit, pagination.offset())
         .await?
         .into_iter()
         .map(Tag::from)
         .collect();
-    Ok((StatusCode::OK, Json(tags)))
+    let total = database::count_user_tags(&state.pool, claims.user_id).await?;
+    Ok((
+        StatusCode::OK,
+        pagination_headers(&uri, total, limit, pagination.offset()),
+        Json(tags),
+    ))
 }
 
+#[instrument(skip_all, fields(audio_id = audio_id))]
 pub async fn delete_audio(
     Extension(state): Extension<AppState>,
     Path(audio_id): Path<i32>,
-    claims: Claims,
+    WriteClaims(claims): WriteClaims,
 ) -> crate::Result<StatusCode> {
-    let deleted = database::delete_audio(&state.pool, claims.user_id, audio_id).await?;
-    if !deleted {
-        return Err(ApiError::NotFound);
-    }
-    state
-        .storage
-        .delete(audio_id)
-        .await
-        .context("failed to remove audio file")?;
+    let user_id = claims.user_id;
+    let storage_state = state.clone();
+    // Keep the row if the file can't be removed, so the delete can be retried.
+    // A file that is already gone is what the delete wanted anyway
+    database::transaction(&state.pool, move |conn| {
+        async move {
+            let audio = database::delete_audio(conn, user_id, audio_id)
+                .await?
+                .ok_or(ApiError::NotFound)?;
+            match storage_state.storage.delete(&audio_key(&audio)).await {
+                Ok(()) => {}
+                Err(StorageError::NotFound) => {
+                    tracing::warn!(audio_id, "audio file was already missing from storage");
+                }
+                Err(err) => Err(err).context("failed to remove audio file")?,
+            }
+            Ok::<_, ApiError>(())
+        }
+        .boxed()
+    })
+    .await?;
     Ok(StatusCode::OK)
 }
 
+#[derive(Deserialize)]
+pub struct RelanguagePayload {
+    language: String,
+}
+
 #[derive(Serialize)]
-pub struct NewAudioBody {
-    id: i32,
+pub struct TranscriptionBody {
+    transcription: Option<String>,
 }
 
-pub async fn new_audio(
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn relanguage_audio(
     Extension(state): Extension<AppState>,
-    claims: Claims,
-    headers: HeaderMap,
-    body: BodyStream,
-) -> crate::Result<(StatusCode, Json<NewAudioBody>)> {
-    let content_type = headers.get(CONTENT_TYPE).ok_or(ApiError::BadRequest)?;
-    if content_type.to_str().map_err(|_| ApiError::BadRequest)? != AUDIO_FILE_MIMETYPE {
+    Path(audio_id): Path<i32>,
+    WriteClaims(claims): WriteClaims,
+    Json(payload): Json<RelanguagePayload>,
+) -> crate::Result<Json<TranscriptionBody>> {
+    if !state.supports_language(&payload.language) {
         return Err(ApiError::BadRequest);
+    }
+    if state.transcriber.get().is_none() {
+        return Err(ApiError::TranscriptionUnavailable);
+    }
+
+    match database::get_audio_by(&state.pool, audio_id, claims.user_id).await? {
+        Some(audio) if audio.user_id == Some(claims.user_id) => {}
+        _ => return Err(ApiError::NotFound),
+    }
+    let claim = claim_transcription(&state, audio_id)
+        .ok_or(ApiError::Conflict("audio is already being transcribed"))?;
+
+    database::update_audio_language(&state.pool, audio_id, &payload.language).await?;
+    // Failures in the old language don't matter anymore, a failure now is
+    // recorded again by `attempt_transcription`
+    database::delete_failed_audio_transcriptions_of(&state.pool, audio_id).await?;
+
+    let attempt = attempt_transcription(&state, audio_id, &payload.language, None).await?;
+    drop(claim);
+    if let Err((failed_audio_transcription_id, err)) = attempt {
+        spawn_transcription_retry(
+            state.clone(),
+            audio_id,
+            payload.language,
+            failed_audio_transcription_id,
+            &err,
+        );
+        return Err(err.into());
+    }
+
+    let audio = database::get_audio_by(&state.pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(TranscriptionBody {
+        transcription: audio.transcription,
+    }))
+}
+
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn get_transcription_history(
+    Extension(pool): Extension<PgPool>,
+    claims: Claims,
+    Path(audio_id): Path<i32>,
+) -> crate::Result<Json<Vec<TranscriptionVersion>>> {
+    if database::get_audio_by(&pool, audio_id, claims.user_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound);
+    }
+
+    let versions = database::get_transcription_versions(&pool, audio_id)
+        .await?
+        .into_iter()
+        .map(TranscriptionVersion::from)
+        .collect();
+    Ok(Json(versions))
+}
+
+/// Make a previous version the current transcription, the revert is recorded
+/// as a new version. Returns the audio with its new transcription.
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn revert_transcription(
+    Extension(pool): Extension<PgPool>,
+    WriteClaims(claims): WriteClaims,
+    Path((audio_id, version_id)): Path<(i32, i32)>,
+) -> crate::Result<Json<Audio>> {
+    if database::get_audio_by(&pool, audio_id, claims.user_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound);
+    }
+
+    let version = database::get_transcription_version(&pool, audio_id, version_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    // The text still comes from the provider of the reverted version
+    let provider = version.provider.as_deref().unwrap_or("unknown");
+    database::update_audio_transcription(
+        &pool,
+        audio_id,
+        &version.transcription,
+        "revert",
+        provider,
+        false,
+    )
+    .await?;
+
+    let (audio, tags) = database::get_audio_with_tags_by(&pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let tags = tags.into_iter().map(Tag::from).collect();
+    Ok(Json(Audio::new(audio, tags, claims.timezone.as_deref())))
+}
+
+#[derive(Deserialize)]
+pub struct RetranscribePayload {
+    language: Option<String>,
+}
+
+/// Clear the transcription of an audio and transcribe it again in the
+/// background, optionally with a different language
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn retranscribe_audio(
+    Extension(state): Extension<AppState>,
+    Path(audio_id): Path<i32>,
+    WriteClaims(claims): WriteClaims,
+    payload: Option<Json<RetranscribePayload>>,
+) -> crate::Result<StatusCode> {
+    let audio = match database::get_audio_by(&state.pool, audio_id, claims.user_id).await? {
+        Some(audio) if audio.user_id == Some(claims.user_id) => audio,
+        _ => return Err(ApiError::NotFound),
     };
+    let claim = claim_transcription(&state, audio_id)
+        .ok_or(ApiError::Conflict("audio is already being transcribed"))?;
 
-    let id = database::insert_audio_by(&state.pool, claims.user_id).await?;
-    tokio::spawn(async move {
-        if let Err(err) = state.storage.store(id, body).await {
-            tracing::error!(?err, audio_id = id, "failed to store audio");
+    let language = match payload.and_then(|Json(payload)| payload.language) {
+        Some(language) => {
+            if !state.supports_language(&language) {
+                return Err(ApiError::BadRequest);
+            }
+            database::update_audio_language(&state.pool, audio_id, &language).await?;
+            language
         }
+        None => audio.language,
+    };
+
+    enqueue_retranscription(state, claim, audio_id, language).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize)]
+pub struct BulkRetranscribePayload {
+    tag: Option<String>,
+    created_from: Option<DateTime<Utc>>,
+    created_to: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct BulkRetranscribeBody {
+    enqueued: usize,
+}
+
+/// Transcribe again every audio of the user matching the tag and/or creation
+/// date range, except the ones with a transcription chosen by the user or
+/// already being transcribed
+#[instrument(skip_all)]
+pub async fn bulk_retranscribe_audios(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    Json(payload): Json<BulkRetranscribePayload>,
+) -> crate::Result<(StatusCode, Json<BulkRetranscribeBody>)> {
+    if payload.tag.is_none() && payload.created_from.is_none() && payload.created_to.is_none() {
+        return Err(field_error(
+            "tag",
+            "a tag or a creation date range is required",
+        ));
+    }
+    if state.transcriber.get().is_none() {
+        return Err(ApiError::TranscriptionUnavailable);
+    }
+
+    let audios = database::get_audios_to_retranscribe(
+        &state.pool,
+        claims.user_id,
+        payload.tag.as_deref(),
+        payload.created_from,
+        payload.created_to,
+    )
+    .await?;
 
-        if let Err(err) = transcribe_and_update_retrying(&state, id, &claims.language, None).await {
+    let mut enqueued = 0;
+    for audio in audios {
+        let Some(claim) = claim_transcription(&state, audio.id) else {
+            continue;
+        };
+        enqueue_retranscription(state.clone(), claim, audio.id, audio.language).await?;
+        enqueued += 1;
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(BulkRetranscribeBody { enqueued }),
+    ))
+}
+
+/// Clear the transcription of an audio and transcribe it again in the
+/// background, with the same retries as new audios. `claim` is held until the
+/// first attempt finishes
+async fn enqueue_retranscription(
+    state: AppState,
+    claim: TranscriptionClaim,
+    audio_id: i32,
+    language: String,
+) -> crate::Result<()> {
+    database::clear_audio_transcription(&state.pool, audio_id).await?;
+
+    let failed_audio_transcription_id =
+        database::get_failed_audio_transcription_of(&state.pool, audio_id).await?;
+    if let Some(failed_audio_transcription_id) = failed_audio_transcription_id {
+        database::reset_failed_audio_transcription(
+            &state.pool,
+            failed_audio_transcription_id,
+            &language,
+        )
+        .await?;
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = transcribe_claimed_retrying(
+            &state,
+            claim,
+            audio_id,
+            &language,
+            failed_audio_transcription_id,
+        )
+        .await
+        {
             tracing::error!(?err, "failed to transcribe and update retrying")
         }
     });
 
-    Ok((StatusCode::CREATED, Json(NewAudioBody { id })))
+    Ok(())
 }
 
-#[instrument]
-pub(crate) fn transcribe_and_update_retrying<'a>(
-    state: &'a AppState,
-    audio_id: i32,
-    language: &'a str,
-    failed_audio_transcription_id: Option<i32>,
-) -> BoxFuture<'a, anyhow::Result<()>> {
-    async move {
-        if let Some(failed_audio_transcription_id) = failed_audio_transcription_id {
-            match database::get_failed_audio_transcription_retries(&state.pool, failed_audio_transcription_id).await.context("failed to get audio transcription retries")? {
-                Some(retries) if retries >= 3 => {
-                    anyhow::bail!("reached maximum retries for failed audio transcription with id: {failed_audio_transcription_id}");
-                }
-                Some(_retries) => {}
-                None => return Ok(())
-            }
+#[derive(Serialize)]
+pub struct NewAudioBody {
+    id: i32,
+    transcription_status: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewAudioQuery {
+    #[serde(default)]
+    sync: bool,
+}
+
+/// Store a new audio and transcribe it in the background.
+///
+/// A title and comma separated tags can be set with the `X-Audio-Title` and
+/// `X-Audio-Tags` headers, both UTF-8. `X-Whisper-Model` picks one of the
+/// allowed whisper models instead of the default.
+///
+/// With `?sync=true` the audio is transcribed before responding and the full
+/// [`Audio`] is returned. This may be slow and is not suitable for large files,
+/// if it takes longer than [`SYNC_TRANSCRIPTION_TIMEOUT`] the transcription is
+/// continued in the background and the audio is returned without it.
+#[instrument(skip_all)]
+pub async fn new_audio(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    client: ClientInfo,
+    Query(query): Query<NewAudioQuery>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> crate::Result<Response> {
+    let content_type = upload_content_type(&state, &headers)?;
+    let title = header_text(&headers, &X_AUDIO_TITLE, "title")?;
+    if title
+        .as_ref()
+        .is_some_and(|title| title.chars().count() > TITLE_MAX_LENGTH)
+    {
+        return Err(field_error(
+            "title",
+            &format!("must be at most {TITLE_MAX_LENGTH} characters"),
+        ));
+    }
+    let tag_names = match header_text(&headers, &X_AUDIO_TAGS, "tags")? {
+        Some(tags) => parse_tag_names(&tags)?,
+        None => Vec::new(),
+    };
+    let model = header_text(&headers, &X_WHISPER_MODEL, "model")?;
+    if let Some(model) = &model {
+        if !state.config.whisper_models.contains(model) {
+            return Err(field_error(
+                "model",
+                &format!("must be one of {}", state.config.whisper_models.join(", ")),
+            ));
         }
+    }
 
-        tracing::info!("getting transcription of audio {audio_id}");
+    let stream = AudioStream::from_stream(body).expected_length(content_length(&headers));
+    let (content_type, stream) = sniff_upload(&state, content_type, stream).await?;
+    let (content_type, stream) = normalize_upload(&state, content_type, stream).await?;
+    let (stream, duration) = probe_upload(&state, content_type, stream).await?;
 
-        match transcribe_and_update(state, audio_id, language).await {
-            Ok(()) => match failed_audio_transcription_id {
-                Some(failed_audio_transcription_id) => {
-                    database::delete_failed_audio_transcription(&state.pool, failed_audio_transcription_id).await?;
-                    Ok(())
-                },
-                None => Ok(())
+    let new_audio = NewAudio {
+        user_id: Some(claims.user_id),
+        language: &claims.language,
+        content_type,
+        title: title.as_deref(),
+        user_agent: client.user_agent.as_deref(),
+        client_ip: Some(client.ip.to_string()),
+        storage_key: new_storage_key(&state, content_type),
+        transcription_model: model.as_deref(),
+    };
+    let id = database::insert_audio(&state.pool, &new_audio).await?;
+    let key = new_audio
+        .storage_key
+        .unwrap_or_else(|| storage_key(id, content_type));
+    store_audio(&state, id, &key, stream).await?;
+    let db_tags = match tag_new_audio(&state, claims.user_id, id, tag_names).await {
+        Ok(db_tags) => db_tags,
+        Err(err) => {
+            // Without its tags the upload is rejected, so it is removed
+            if let Err(err) = database::delete_audio(&state.pool, claims.user_id, id).await {
+                tracing::error!(?err, audio_id = id, "failed to delete audio");
             }
-            Err(err) => {
-                tracing::error!(?err, audio_id, "failed to transcribe audio");
-
-                let failed_audio_transcription_id = match failed_audio_transcription_id {
-                    Some(failed_audio_transcription_id) => {
-                        database::update_failed_audio_transcription(&state.pool, failed_audio_transcription_id).await?;
-                        failed_audio_transcription_id
-                    },
-                    None => {
-                        database::insert_failed_audio_transcription(&state.pool, audio_id, language).await?
-                    }
-                };
+            if let Err(err) = state.storage.delete(&key).await {
+                tracing::error!(?err, audio_id = id, "failed to delete audio file");
+            }
+            return Err(err);
+        }
+    };
+    log_audio_event(&state, id, database::AUDIO_EVENT_UPLOADED, None).await;
 
-                // wait a minute before retrying
-                let duration = Duration::from_secs(60u64);
-                tracing::info!("retrying transcription of audio {audio_id} in {duration:?}");
-                tokio::time::sleep(duration).await;
+    let tags: Vec<Tag> = db_tags.into_iter().map(Tag::from).collect();
 
-                transcribe_and_update_retrying(
-                    state,
-                    audio_id,
-                    language,
-                    Some(failed_audio_transcription_id),
-                )
-                .await
+    if mark_if_too_short(&state, id, duration).await? {
+        if query.sync {
+            let audio = database::get_audio_by(&state.pool, id, claims.user_id)
+                .await?
+                .ok_or(ApiError::NotFound)?;
+            let audio = Audio::new(audio, tags, claims.timezone.as_deref());
+            return Ok((StatusCode::CREATED, Json(audio)).into_response());
+        }
+        let body = NewAudioBody {
+            id,
+            transcription_status: database::TRANSCRIPTION_TOO_SHORT,
+        };
+        return Ok((StatusCode::CREATED, Json(body)).into_response());
+    }
+
+    // The audio is new, if another transcription claimed it already that one
+    // is left to finish it
+    let sync_claim = (query.sync && state.transcriber.get().is_some())
+        .then(|| claim_transcription(&state, id))
+        .flatten();
+    if let Some(claim) = sync_claim {
+        let transcription = tokio::time::timeout(
+            SYNC_TRANSCRIPTION_TIMEOUT,
+            attempt_transcription(&state, id, &claims.language, None),
+        )
+        .await;
+        drop(claim);
+        match transcription {
+            Ok(attempt) => {
+                if let Err((failed_audio_transcription_id, err)) = attempt? {
+                    spawn_transcription_retry(
+                        state.clone(),
+                        id,
+                        claims.language,
+                        failed_audio_transcription_id,
+                        &err,
+                    );
+                }
+            }
+            Err(_) => {
+                tracing::warn!(audio_id = id, "synchronous transcription timed out");
+                spawn_transcription(state.clone(), id, claims.language);
             }
         }
+
+        let audio = database::get_audio_by(&state.pool, id, claims.user_id)
+            .await?
+            .ok_or(ApiError::NotFound)?;
+        return Ok((
+            StatusCode::CREATED,
+            Json(Audio::new(audio, tags, claims.timezone.as_deref())),
+        )
+            .into_response());
     }
-    .in_current_span()
-    .boxed()
+
+    spawn_transcription(state, id, claims.language);
+
+    let body = NewAudioBody {
+        id,
+        transcription_status: database::TRANSCRIPTION_PENDING,
+    };
+    Ok((StatusCode::CREATED, Json(body)).into_response())
 }
 
-#[instrument]
-async fn transcribe_and_update(
-    state: &AppState,
-    audio_id: i32,
-    language: &str,
-) -> anyhow::Result<()> {
-    let file = state.storage.get(audio_id).await?;
-    let transcription = state.stt.transcribe(file, language).await?;
-    database::update_audio_transcription(&state.pool, audio_id, &transcription)
-        .await
-        .context("failed to update audio transcription")?;
+#[derive(Deserialize)]
+pub struct ImportAudioPayload {
+    url: String,
+    title: Option<String>,
+    language: Option<String>,
+}
+
+/// Download an audio from a public url, then store and transcribe it like
+/// [`new_audio`] does
+#[instrument(skip_all)]
+pub async fn import_audio(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    client: ClientInfo,
+    Json(payload): Json<ImportAudioPayload>,
+) -> crate::Result<(StatusCode, Json<NewAudioBody>)> {
+    let language = payload.language.unwrap_or(claims.language);
+    if !state.supports_language(&language) {
+        return Err(field_error("language", "is not supported"));
+    }
+
+    let url = reqwest::Url::parse(&payload.url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| field_error("url", "must be an http or https url"))?;
+    if !crate::import::is_public_url(&url) {
+        return Err(field_error("url", "must be a public address"));
+    }
+
+    let response = state
+        .import_client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| {
+            tracing::info!(?err, "failed to fetch audio to import");
+            field_error("url", "could not be fetched")
+        })?;
+
+    if response
+        .content_length()
+        .is_some_and(|length| length > crate::MAX_BYTES_TO_SAVE as u64)
+    {
+        return Err(field_error("url", "is too large"));
+    }
+
+    // Remote servers often add parameters like charset, only the type matters
+    let content_type = upload_content_type(&state, response.headers())?.to_string();
+
+    let content_length = response.content_length();
+    let body = limit_stream(response.bytes_stream(), crate::MAX_BYTES_TO_SAVE);
+    let stream = AudioStream::from_stream(body).expected_length(content_length);
+    let (content_type, stream) = normalize_upload(&state, &content_type, stream).await?;
+    let (stream, duration) = probe_upload(&state, content_type, stream).await?;
+
+    let new_audio = NewAudio {
+        user_id: Some(claims.user_id),
+        language: &language,
+        content_type,
+        title: payload.title.as_deref(),
+        user_agent: client.user_agent.as_deref(),
+        client_ip: Some(client.ip.to_string()),
+        storage_key: new_storage_key(&state, content_type),
+        transcription_model: None,
+    };
+    let id = database::insert_audio(&state.pool, &new_audio).await?;
+    let key = new_audio
+        .storage_key
+        .unwrap_or_else(|| storage_key(id, content_type));
+    store_audio(&state, id, &key, stream).await?;
+    log_audio_event(&state, id, database::AUDIO_EVENT_UPLOADED, None).await;
+
+    let transcription_status = if mark_if_too_short(&state, id, duration).await? {
+        database::TRANSCRIPTION_TOO_SHORT
+    } else {
+        spawn_transcription(state, id, language);
+        database::TRANSCRIPTION_PENDING
+    };
+
+    Ok((
+        StatusCode::CREATED,
+        Json(NewAudioBody {
+            id,
+            transcription_status,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct PresignUploadPayload {
+    content_type: String,
+}
+
+#[derive(Serialize)]
+pub struct PresignUploadBody {
+    url: String,
+    /// Sent to `confirm_upload` after uploading to `url`
+    upload_token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Signed claims of a presigned upload, so only the user that requested it
+/// can confirm it
+#[derive(Serialize, Deserialize)]
+struct UploadClaims {
+    aud: String,
+    user_id: i32,
+    storage_key: String,
+    content_type: String,
+    exp: i64,
+}
+
+/// Get a url to upload an audio directly to the storage, the audio is created
+/// later by [`confirm_upload`]. Direct uploads are never normalized.
+#[instrument(skip_all)]
+pub async fn presign_upload(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    Json(payload): Json<PresignUploadPayload>,
+) -> crate::Result<Json<PresignUploadBody>> {
+    let content_type = allowed_content_type(&state, &payload.content_type)?;
+    let storage_key = random_storage_key(content_type);
+    let expires_at = Utc::now() + chrono::Duration::minutes(PRESIGN_EXPIRATION_MINUTES);
+    let url = state
+        .storage
+        .presign(&storage_key, PresignAction::Upload, expires_at)
+        .await?;
+    // Keeps `reconcile_storage` from deleting the file before it is confirmed
+    database::insert_pending_upload(&state.pool, &storage_key, claims.user_id, expires_at).await?;
+
+    let upload_claims = UploadClaims {
+        aud: UPLOAD_TOKEN_AUDIENCE.to_string(),
+        user_id: claims.user_id,
+        storage_key,
+        content_type: content_type.to_string(),
+        exp: expires_at.timestamp(),
+    };
+    let upload_token = encode(&Header::default(), &upload_claims, &state.keys.encoding)
+        .context("failed encoding upload token")?;
+
+    Ok(Json(PresignUploadBody {
+        url,
+        upload_token,
+        expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmUploadPayload {
+    upload_token: String,
+    title: Option<String>,
+}
+
+/// Create the audio of a presigned upload and transcribe it in the background
+#[instrument(skip_all)]
+pub async fn confirm_upload(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    client: ClientInfo,
+    Json(payload): Json<ConfirmUploadPayload>,
+) -> crate::Result<(StatusCode, Json<NewAudioBody>)> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[UPLOAD_TOKEN_AUDIENCE]);
+    let upload = decode::<UploadClaims>(&payload.upload_token, &state.keys.decoding, &validation)
+        .ok()
+        .map(|token_data| token_data.claims)
+        .filter(|upload| upload.user_id == claims.user_id)
+        .ok_or_else(|| field_error("upload_token", "is invalid or expired"))?;
+    // Each upload can only be confirmed once
+    if !database::is_pending_upload(&state.pool, &upload.storage_key).await? {
+        return Err(field_error("upload_token", "is invalid or expired"));
+    }
+
+    let title = payload
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| !title.is_empty());
+    if title.is_some_and(|title| title.chars().count() > TITLE_MAX_LENGTH) {
+        return Err(field_error(
+            "title",
+            &format!("must be at most {TITLE_MAX_LENGTH} characters"),
+        ));
+    }
+
+    let content_type = allowed_content_type(&state, &upload.content_type)?;
+    let size = match state.storage.size(&upload.storage_key).await {
+        Ok(size) => size,
+        Err(StorageError::NotFound) => {
+            return Err(field_error("upload_token", "no audio was uploaded"))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // Presigned urls can't limit the size, so it is only known now
+    if size > crate::MAX_BYTES_TO_SAVE as u64 {
+        if let Err(err) = state.storage.delete(&upload.storage_key).await {
+            tracing::error!(?err, "failed to delete oversized upload");
+        }
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    // The presigned url can still be written to, so the audio is copied to a
+    // key the client never had, checking it again while it is read
+    let stream = state.storage.get(&upload.storage_key).await?;
+    let stream = AudioStream::from_stream(limit_stream(stream, crate::MAX_BYTES_TO_SAVE));
+    let (content_type, stream) = sniff_upload(&state, content_type, stream).await?;
+    let (stream, duration) = probe_upload(&state, content_type, stream).await?;
+
+    let new_audio = NewAudio {
+        user_id: Some(claims.user_id),
+        language: &claims.language,
+        content_type,
+        title,
+        user_agent: client.user_agent.as_deref(),
+        client_ip: Some(client.ip.to_string()),
+        storage_key: new_storage_key(&state, content_type),
+        transcription_model: None,
+    };
+    let id = database::insert_audio(&state.pool, &new_audio).await?;
+    let key = new_audio
+        .storage_key
+        .unwrap_or_else(|| storage_key(id, content_type));
+    store_audio(&state, id, &key, stream).await?;
+    if let Err(err) = state.storage.delete(&upload.storage_key).await {
+        tracing::error!(?err, id, "failed to delete confirmed upload");
+    }
+    database::delete_pending_upload(&state.pool, &upload.storage_key).await?;
+    log_audio_event(&state, id, database::AUDIO_EVENT_UPLOADED, None).await;
+
+    let transcription_status = if mark_if_too_short(&state, id, duration).await? {
+        database::TRANSCRIPTION_TOO_SHORT
+    } else {
+        spawn_transcription(state, id, claims.language);
+        database::TRANSCRIPTION_PENDING
+    };
+
+    Ok((
+        StatusCode::CREATED,
+        Json(NewAudioBody {
+            id,
+            transcription_status,
+        }),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct PresignDownloadBody {
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Get a url to download the file of an audio directly from the storage
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn presign_download(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(audio_id): Path<i32>,
+) -> crate::Result<Json<PresignDownloadBody>> {
+    let audio = match database::get_audio_by(&state.pool, audio_id, claims.user_id).await? {
+        Some(audio) if audio.user_id == Some(claims.user_id) => audio,
+        _ => return Err(ApiError::NotFound),
+    };
+
+    let expires_at = Utc::now() + chrono::Duration::minutes(PRESIGN_EXPIRATION_MINUTES);
+    let url = state
+        .storage
+        .presign(&audio_key(&audio), PresignAction::Download, expires_at)
+        .await?;
+
+    Ok(Json(PresignDownloadBody { url, expires_at }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GuestAudioQuery {
+    language: String,
+}
+
+#[derive(Serialize)]
+pub struct GuestAudioBody {
+    id: i32,
+    claim_token: String,
+    claim_expires_at: DateTime<Utc>,
+}
+
+/// Store and transcribe an audio without an account.
+///
+/// The returned claim token can later be used by a user to move the audio to
+/// their account with [`claim_audio`], unclaimed audios are deleted when the
+/// token expires.
+#[instrument(skip_all)]
+pub async fn new_guest_audio(
+    Extension(state): Extension<AppState>,
+    client: ClientInfo,
+    Query(query): Query<GuestAudioQuery>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> crate::Result<(StatusCode, Json<GuestAudioBody>)> {
+    let config = &state.config;
+    if !config.guest_uploads_enabled {
+        return Err(ApiError::NotFound);
+    }
+
+    if !state.supports_language(&query.language) {
+        return Err(field_error("language", "is not supported"));
+    }
+    let content_type = upload_content_type(&state, &headers)?;
+    let content_length = content_length(&headers);
+    if content_length.is_some_and(|length| length > config.guest_max_bytes as u64) {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let client_ip = client.ip.to_string();
+    let since = Utc::now() - chrono::Duration::hours(1);
+    let recent = database::count_guest_audios_from(&state.pool, &client_ip, since).await?;
+    if recent >= config.guest_uploads_per_hour {
+        return Err(ApiError::TooManyRequests);
+    }
+
+    let body = limit_stream(body, config.guest_max_bytes);
+    let stream = AudioStream::from_stream(body).expected_length(content_length);
+    let (content_type, stream) = sniff_upload(&state, content_type, stream).await?;
+    let (content_type, stream) = normalize_upload(&state, content_type, stream).await?;
+    let (stream, duration) = probe_upload(&state, content_type, stream).await?;
+
+    let claim_token = generate_token(&state.rand_rng, config.reset_token_bytes)?;
+    let claim_expires_at = Utc::now() + chrono::Duration::hours(config.guest_audio_ttl_hours);
+    let new_audio = NewAudio {
+        user_id: None,
+        language: &query.language,
+        content_type,
+        title: None,
+        user_agent: client.user_agent.as_deref(),
+        client_ip: Some(client_ip),
+        storage_key: new_storage_key(&state, content_type),
+        transcription_model: None,
+    };
+    let id = database::insert_guest_audio(
+        &state.pool,
+        &new_audio,
+        &hash(&claim_token)?,
+        claim_expires_at,
+    )
+    .await?;
+    let key = new_audio
+        .storage_key
+        .unwrap_or_else(|| storage_key(id, content_type));
+    store_audio(&state, id, &key, stream).await?;
+    log_audio_event(&state, id, database::AUDIO_EVENT_UPLOADED, None).await;
+
+    if !mark_if_too_short(&state, id, duration).await? {
+        spawn_transcription(state, id, query.language);
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(GuestAudioBody {
+            id,
+            claim_token,
+            claim_expires_at,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ClaimAudioPayload {
+    claim_token: String,
+}
+
+/// Move a guest audio to the account of the user
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub async fn claim_audio(
+    Extension(pool): Extension<PgPool>,
+    WriteClaims(claims): WriteClaims,
+    Path(audio_id): Path<i32>,
+    Json(payload): Json<ClaimAudioPayload>,
+) -> crate::Result<Json<Audio>> {
+    let claim_token = database::get_audio_claim_token(&pool, audio_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let parsed_hash = PasswordHash::new(&claim_token).map_err(|_| ApiError::InternalServerError)?;
+    if Argon2::default()
+        .verify_password(payload.claim_token.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Err(ApiError::NotFound);
+    }
+
+    if !database::claim_audio(&pool, audio_id, claims.user_id).await? {
+        return Err(ApiError::NotFound);
+    }
+
+    let audio = database::get_audio_by(&pool, audio_id, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(Audio::new(
+        audio,
+        Vec::new(),
+        claims.timezone.as_deref(),
+    )))
+}
+
+/// Re-encode the upload when `NORMALIZE_AUDIO_ON_UPLOAD` is enabled, returns
+/// the content type it has to be stored with
+async fn normalize_upload<'a>(
+    state: &AppState,
+    content_type: &'a str,
+    stream: AudioStream,
+) -> crate::Result<(&'a str, AudioStream)> {
+    if !state.config.normalize_audio_on_upload {
+        return Ok((content_type, stream));
+    }
+
+    let extension = file_extension(content_type).unwrap_or(".webm");
+    match ffmpeg::normalize(stream, extension, &state.config.tmp_dir).await {
+        Ok(stream) => Ok((ffmpeg::NORMALIZED_CONTENT_TYPE, stream)),
+        Err(err) => {
+            tracing::info!(?err, "failed to normalize uploaded audio");
+            Err(field_error("file", "is not a valid audio"))
+        }
+    }
+}
+
+/// `content_type` as configured in `ALLOWED_AUDIO_TYPES`, if its media type
+/// is allowed
+fn allowed_content_type<'a>(state: &'a AppState, content_type: &str) -> crate::Result<&'a str> {
+    let media_type = media_type(content_type);
+    state
+        .config
+        .allowed_audio_types
+        .iter()
+        .find(|allowed| media_type.as_ref() == Some(*allowed))
+        .map(String::as_str)
+        .ok_or_else(|| ApiError::UnsupportedMediaType(state.config.allowed_audio_types.clone()))
+}
+
+/// The `Content-Type` of an upload, with its parameters, if its media type is
+/// one of the allowed audio types and it fits in `audios.content_type`
+fn upload_content_type<'a>(state: &AppState, headers: &'a HeaderMap) -> crate::Result<&'a str> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .ok_or_else(|| ApiError::UnsupportedMediaType(state.config.allowed_audio_types.clone()))?;
+    allowed_content_type(state, content_type)?;
+    let content_type = content_type.trim();
+    if content_type.len() > CONTENT_TYPE_MAX_LENGTH {
+        return Err(field_error(
+            "content_type",
+            &format!("must be at most {CONTENT_TYPE_MAX_LENGTH} bytes"),
+        ));
+    }
+    Ok(content_type)
+}
+
+/// With `SNIFF_AUDIO_UPLOADS` check the start of the upload is an allowed
+/// audio format, returns the content type it has to be stored with
+async fn sniff_upload<'a>(
+    state: &'a AppState,
+    content_type: &'a str,
+    stream: AudioStream,
+) -> crate::Result<(&'a str, AudioStream)> {
+    if !state.config.sniff_audio_uploads {
+        return Ok((content_type, stream));
+    }
+
+    let (prefix, stream) = stream
+        .peek(SNIFF_LENGTH)
+        .await
+        .context("failed to read the start of the upload")?;
+    let Some(sniffed) = sniff_content_type(&prefix) else {
+        return Err(field_error("file", "is not a supported audio"));
+    };
+    if media_type(content_type).as_deref() == Some(sniffed) {
+        return Ok((content_type, stream));
+    }
+    tracing::info!(
+        content_type,
+        sniffed,
+        "upload is not the declared audio type"
+    );
+    Ok((allowed_content_type(state, sniffed)?, stream))
+}
+
+/// The key to save with a new audio, None when it should be keyed by its id
+fn new_storage_key(state: &AppState, content_type: &str) -> Option<String> {
+    match state.config.storage_key_scheme {
+        StorageKeyScheme::Id => None,
+        StorageKeyScheme::Random => Some(random_storage_key(content_type)),
+    }
+}
+
+/// The trimmed value of the `name` header, None if it is missing or empty
+fn header_text(
+    headers: &HeaderMap,
+    name: &HeaderName,
+    field: &'static str,
+) -> crate::Result<Option<String>> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    let value = std::str::from_utf8(value.as_bytes())
+        .map_err(|_| field_error(field, "must be valid UTF-8"))?
+        .trim();
+    Ok((!value.is_empty()).then(|| value.to_string()))
+}
+
+/// Split comma separated tag names, skipping empty and repeated ones
+fn parse_tag_names(tags: &str) -> crate::Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for name in tags.split(',').map(str::trim) {
+        if name.is_empty() || names.iter().any(|existing| existing == name) {
+            continue;
+        }
+        if !is_valid_tag_name(name) {
+            return Err(field_error(
+                "tags",
+                &format!("must be at most {TAG_NAME_MAX_LENGTH} characters each"),
+            ));
+        }
+        names.push(name.to_string());
+    }
+    Ok(names)
+}
+
+fn is_valid_tag_name(name: &str) -> bool {
+    !name.trim().is_empty() && name.chars().count() <= TAG_NAME_MAX_LENGTH
+}
+
+/// Create the tags of a new audio and tag it with them, all or none of them
+async fn tag_new_audio(
+    state: &AppState,
+    user_id: i32,
+    audio_id: i32,
+    tag_names: Vec<String>,
+) -> crate::Result<Vec<database::DbTag>> {
+    if tag_names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let max_tags = state.config.max_tags_per_user;
+    database::transaction(&state.pool, move |conn| {
+        async move {
+            let mut db_tags = Vec::with_capacity(tag_names.len());
+            for name in &tag_names {
+                let db_tag = database::get_or_create_tag(conn, user_id, name, None, max_tags)
+                    .await?
+                    .ok_or(ApiError::LimitExceeded("maximum number of tags reached"))?;
+                database::tag_audio(&mut *conn, db_tag.id, audio_id).await?;
+                db_tags.push(db_tag);
+            }
+            Ok::<_, ApiError>(db_tags)
+        }
+        .boxed()
+    })
+    .await
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Fail the stream once it has yielded more than `max_bytes`
+fn limit_stream<S, E>(
+    stream: S,
+    max_bytes: usize,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<anyhow::Error>,
+{
+    let mut received = 0;
+    stream.map(move |chunk| {
+        let chunk = chunk.map_err(Into::into)?;
+        received += chunk.len();
+        if received > max_bytes {
+            anyhow::bail!("audio is larger than {max_bytes} bytes");
+        }
+        Ok(chunk)
+    })
+}
+
+fn field_error(field: &'static str, message: &str) -> ApiError {
+    ApiError::ValidationError(vec![FieldError {
+        field,
+        message: message.to_string(),
+    }])
+}
+
+/// Probe the duration of an upload before it is stored, when
+/// `MIN_AUDIO_DURATION_MS` is set. The duration is None if it isn't needed or
+/// can't be probed
+async fn probe_upload(
+    state: &AppState,
+    content_type: &str,
+    stream: AudioStream,
+) -> crate::Result<(AudioStream, Option<Duration>)> {
+    if state.config.min_audio_duration_ms == 0 {
+        return Ok((stream, None));
+    }
+
+    let extension = file_extension(content_type).unwrap_or(".webm");
+    match ffmpeg::probe(stream, extension, &state.config.tmp_dir).await {
+        Ok(probed) => Ok(probed),
+        // A body over `RequestBodyLimitLayer`'s limit fails while being written
+        Err(err) if err.chain().any(crate::api_error::is_length_limit_error) => {
+            Err(ApiError::PayloadTooLarge)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Mark the audio as too short to be transcribed if its `duration` is shorter
+/// than `MIN_AUDIO_DURATION_MS`. If its duration is unknown it is transcribed
+/// anyway
+async fn mark_if_too_short(
+    state: &AppState,
+    audio_id: i32,
+    duration: Option<Duration>,
+) -> crate::Result<bool> {
+    let min_duration = Duration::from_millis(state.config.min_audio_duration_ms);
+    let Some(duration) = duration else {
+        return Ok(false);
+    };
+    if duration >= min_duration {
+        return Ok(false);
+    }
+
+    tracing::info!(
+        audio_id,
+        ?duration,
+        "not transcribing audio, it is too short"
+    );
+    database::update_audio_transcription_status(
+        &state.pool,
+        audio_id,
+        database::TRANSCRIPTION_TOO_SHORT,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Store the file of a just inserted audio, deleting the audio if it fails
+async fn store_audio(
+    state: &AppState,
+    audio_id: i32,
+    key: &str,
+    stream: AudioStream,
+) -> crate::Result<()> {
+    if let Err(err) = state.storage.store(key, stream).await {
+        tracing::error!(?err, audio_id, "failed to store audio");
+        database::delete_audios(&state.pool, &[audio_id]).await?;
+        return Err(err.into());
+    }
     Ok(())
 }
+
+/// Add an event to the timeline of an audio, failing to do so is only logged
+async fn log_audio_event(state: &AppState, audio_id: i32, event_type: &str, error: Option<&str>) {
+    if let Err(err) = database::insert_audio_event(&state.pool, audio_id, event_type, error).await {
+        tracing::error!(?err, audio_id, event_type, "failed to insert audio event");
+    }
+}
+
+/// Why a transcription failed, short enough to show to the owner of the audio.
+/// The whole error is only logged
+fn transcription_failure_reason(err: &anyhow::Error) -> &'static str {
+    match err.downcast_ref::<TranscriptionError>() {
+        Some(TranscriptionError::RateLimited { .. }) => {
+            "rate limited by the speech to text provider"
+        }
+        Some(TranscriptionError::ServiceUnavailable) => "speech to text provider is unavailable",
+        Some(TranscriptionError::UnexpectedResponse(_)) => {
+            "unexpected response from the speech to text provider"
+        }
+        _ if err
+            .chain()
+            .filter_map(|err| err.downcast_ref::<reqwest::Error>())
+            .any(reqwest::Error::is_timeout) =>
+        {
+            "speech to text provider timed out"
+        }
+        _ => "transcription failed",
+    }
+}
+
+fn spawn_transcription(state: AppState, audio_id: i32, language: String) {
+    tokio::spawn(async move {
+        if let Err(err) = transcribe_and_update_retrying(&state, audio_id, &language, None).await {
+            tracing::error!(?err, "failed to transcribe and update retrying")
+        }
+    });
+}
+
+/// Retries allowed for failed transcriptions of the audio, set per user
+async fn audio_max_retries(state: &AppState, audio_id: i32) -> anyhow::Result<i16> {
+    let user_id = database::get_audio(&state.pool, audio_id)
+        .await
+        .context("failed to get audio")?
+        .and_then(|audio| audio.user_id);
+    match user_id {
+        Some(user_id) => database::get_user_max_retries(&state.pool, user_id)
+            .await
+            .context("failed to get user max retries"),
+        None => Ok(database::DEFAULT_MAX_TRANSCRIPTION_RETRIES),
+    }
+}
+
+/// Marks an audio as being transcribed until dropped
+pub(crate) struct TranscriptionClaim {
+    state: AppState,
+    audio_id: i32,
+}
+
+impl Drop for TranscriptionClaim {
+    fn drop(&mut self) {
+        self.state
+            .transcribing
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&self.audio_id);
+    }
+}
+
+/// Claim the transcription of an audio, None if it is already being
+/// transcribed. Every transcription holds the claim of its audio, so two of
+/// them can't overwrite each other's result
+pub(crate) fn claim_transcription(state: &AppState, audio_id: i32) -> Option<TranscriptionClaim> {
+    let is_new = state
+        .transcribing
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(audio_id);
+    is_new.then(|| TranscriptionClaim {
+        state: state.clone(),
+        audio_id,
+    })
+}
+
+/// Transcribe an audio once, keeping its failed transcription up to date. When
+/// it fails, returns the id of the failed transcription along with the error
+pub(crate) async fn attempt_transcription(
+    state: &AppState,
+    audio_id: i32,
+    language: &str,
+    failed_audio_transcription_id: Option<i32>,
+) -> anyhow::Result<Result<(), (i32, anyhow::Error)>> {
+    if failed_audio_transcription_id.is_some() {
+        log_audio_event(state, audio_id, database::AUDIO_EVENT_RETRIED, None).await;
+    }
+    log_audio_event(
+        state,
+        audio_id,
+        database::AUDIO_EVENT_TRANSCRIPTION_STARTED,
+        None,
+    )
+    .await;
+    tracing::info!("getting transcription of audio {audio_id}");
+
+    match transcribe_and_update(state, audio_id, language).await {
+        Ok(()) => {
+            log_audio_event(state, audio_id, database::AUDIO_EVENT_COMPLETED, None).await;
+            if let Some(failed_audio_transcription_id) = failed_audio_transcription_id {
+                database::delete_failed_audio_transcription(
+                    &state.pool,
+                    failed_audio_transcription_id,
+                )
+                .await?;
+            }
+            Ok(Ok(()))
+        }
+        Err(err) => {
+            tracing::error!(?err, audio_id, "failed to transcribe audio");
+            log_audio_event(
+                state,
+                audio_id,
+                database::AUDIO_EVENT_TRANSCRIPTION_FAILED,
+                Some(transcription_failure_reason(&err)),
+            )
+            .await;
+
+            let failed_audio_transcription_id = match failed_audio_transcription_id {
+                Some(failed_audio_transcription_id) => {
+                    database::update_failed_audio_transcription(
+                        &state.pool,
+                        failed_audio_transcription_id,
+                    )
+                    .await?;
+                    failed_audio_transcription_id
+                }
+                None => {
+                    database::insert_failed_audio_transcription(&state.pool, audio_id, language)
+                        .await?
+                }
+            };
+            Ok(Err((failed_audio_transcription_id, err)))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(audio_id = audio_id))]
+pub(crate) fn transcribe_and_update_retrying<'a>(
+    state: &'a AppState,
+    audio_id: i32,
+    language: &'a str,
+    failed_audio_transcription_id: Option<i32>,
+) -> BoxFuture<'a, anyhow::Result<()>> {
+    async move {
+        if let Some(failed_audio_transcription_id) = failed_audio_transcription_id {
+            match database::get_failed_audio_transcription_retries(&state.pool, failed_audio_transcription_id).await.context("failed to get audio transcription retries")? {
+                Some(retries) => {
+                    let max_retries = audio_max_retries(state, audio_id).await?;
+                    if retries >= i32::from(max_retries) {
+                        anyhow::bail!("reached maximum retries for failed audio transcription with id: {failed_audio_transcription_id}");
+                    }
+                }
+                None => return Ok(())
+            }
+        }
+
+        let Some(claim) = claim_transcription(state, audio_id) else {
+            tracing::info!(audio_id, "audio is already being transcribed");
+            return Ok(());
+        };
+        transcribe_claimed_retrying(state, claim, audio_id, language, failed_audio_transcription_id)
+            .await
+    }
+    .in_current_span()
+    .boxed()
+}
+
+/// Like [`transcribe_and_update_retrying`] for an audio that was already
+/// claimed, the claim is released before waiting to retry
+async fn transcribe_claimed_retrying(
+    state: &AppState,
+    claim: TranscriptionClaim,
+    audio_id: i32,
+    language: &str,
+    failed_audio_transcription_id: Option<i32>,
+) -> anyhow::Result<()> {
+    if state.transcriber.get().is_none() {
+        tracing::info!("speech to text is unavailable, audio {audio_id} stays pending");
+        return Ok(());
+    }
+
+    let (failed_audio_transcription_id, err) =
+        match attempt_transcription(state, audio_id, language, failed_audio_transcription_id)
+            .await?
+        {
+            Ok(()) => return Ok(()),
+            Err(failed) => failed,
+        };
+    drop(claim);
+
+    retry_transcription_after(
+        state,
+        audio_id,
+        language,
+        failed_audio_transcription_id,
+        retry_delay(&err),
+    )
+    .await
+}
+
+/// How long to wait before retrying a failed transcription: a minute, unless
+/// the provider told us how long to wait
+fn retry_delay(err: &anyhow::Error) -> Duration {
+    match err.downcast_ref::<TranscriptionError>() {
+        Some(TranscriptionError::RateLimited { retry_after_secs }) => {
+            Duration::from_secs(*retry_after_secs)
+        }
+        _ => Duration::from_secs(60u64),
+    }
+}
+
+async fn retry_transcription_after(
+    state: &AppState,
+    audio_id: i32,
+    language: &str,
+    failed_audio_transcription_id: i32,
+    delay: Duration,
+) -> anyhow::Result<()> {
+    tracing::info!("retrying transcription of audio {audio_id} in {delay:?}");
+    tokio::time::sleep(delay).await;
+
+    transcribe_and_update_retrying(
+        state,
+        audio_id,
+        language,
+        Some(failed_audio_transcription_id),
+    )
+    .await
+}
+
+/// Retry a transcription that just failed in the background, see
+/// [`retry_delay`]
+fn spawn_transcription_retry(
+    state: AppState,
+    audio_id: i32,
+    language: String,
+    failed_audio_transcription_id: i32,
+    err: &anyhow::Error,
+) {
+    let delay = retry_delay(err);
+    tokio::spawn(async move {
+        if let Err(err) = retry_transcription_after(
+            &state,
+            audio_id,
+            &language,
+            failed_audio_transcription_id,
+            delay,
+        )
+        .await
+        {
+            tracing::error!(?err, "failed to transcribe and update retrying")
+        }
+    });
+}
+
+#[instrument(skip_all, fields(audio_id = audio_id))]
+async fn transcribe_and_update(
+    state: &AppState,
+    audio_id: i32,
+    language: &str,
+) -> anyhow::Result<()> {
+    let audio = database::get_audio(&state.pool, audio_id)
+        .await?
+        .context("audio to transcribe does not exist")?;
+    let extension = file_extension(&audio.content_type).unwrap_or(".webm");
+    let model = audio.transcription_model.as_deref();
+    let file = state.storage.get(&audio_key(&audio)).await?;
+    let transcriber = state
+        .transcriber
+        .get()
+        .context("speech to text is unavailable")?;
+    let transcription = if state.config.transcription_chunk_secs == 0 {
+        transcribe_with_permit(transcriber, file, extension, language, model).await?
+    } else {
+        transcribe_in_chunks(
+            state,
+            transcriber,
+            audio_id,
+            file,
+            extension,
+            language,
+            model,
+        )
+        .await?
+    };
+    if transcription.text.trim().is_empty() {
+        match state.config.empty_transcriptions {
+            EmptyTranscription::Keep => {}
+            EmptyTranscription::Fail => anyhow::bail!("transcription is empty"),
+            EmptyTranscription::Mark => {
+                tracing::info!(audio_id, "transcription is empty");
+                database::update_audio_transcription_status(
+                    &state.pool,
+                    audio_id,
+                    database::TRANSCRIPTION_EMPTY,
+                )
+                .await
+                .context("failed to mark audio transcription as empty")?;
+                database::delete_transcription_chunks(&state.pool, audio_id)
+                    .await
+                    .context("failed to delete transcription chunks")?;
+                return Ok(());
+            }
+        }
+    }
+    let needs_review = transcription
+        .confidence
+        .is_some_and(|confidence| confidence < state.config.review_confidence_threshold);
+    database::update_audio_detected_language(
+        &state.pool,
+        audio_id,
+        transcription.detected_language.as_deref(),
+    )
+    .await
+    .context("failed to update detected language")?;
+    let mut transcription = transcription.text;
+    for post_processor in &state.post_processors {
+        transcription = post_processor
+            .process(transcription, language)
+            .context("failed to post-process transcription")?;
+    }
+    database::update_audio_transcription(
+        &state.pool,
+        audio_id,
+        &transcription,
+        transcriber.stt.name(),
+        transcriber.stt.name(),
+        needs_review,
+    )
+    .await
+    .context("failed to update audio transcription")?;
+    if let Some(title) = suggest_title(&transcription) {
+        database::set_auto_title_if_empty(&state.pool, audio_id, &title)
+            .await
+            .context("failed to set audio title")?;
+    }
+    // The transcription is already saved, failing here must not retry it
+    if let Some(user_id) = audio.user_id {
+        if let Err(err) = apply_tag_rules(state, audio_id, user_id, &transcription).await {
+            tracing::error!(?err, audio_id, "failed to apply tag rules");
+        }
+    }
+    database::delete_transcription_chunks(&state.pool, audio_id)
+        .await
+        .context("failed to delete transcription chunks")?;
+    Ok(())
+}
+
+async fn transcribe_with_permit(
+    transcriber: &Transcriber,
+    file: AudioStream,
+    extension: &str,
+    language: &str,
+    model: Option<&str>,
+) -> anyhow::Result<Transcription> {
+    let _permit = transcriber
+        .permits
+        .acquire()
+        .await
+        .context("transcription permits closed")?;
+    Ok(transcriber
+        .stt
+        .transcribe(file, extension, language, model)
+        .await?)
+}
+
+/// Transcribe audios longer than `TRANSCRIPTION_CHUNK_SECS` one chunk at a
+/// time. Every chunk is saved once transcribed, so a failed transcription
+/// continues from the first missing chunk when retried.
+async fn transcribe_in_chunks(
+    state: &AppState,
+    transcriber: &Transcriber,
+    audio_id: i32,
+    file: AudioStream,
+    extension: &str,
+    language: &str,
+    model: Option<&str>,
+) -> anyhow::Result<Transcription> {
+    let chunk_secs = state.config.transcription_chunk_secs;
+    let tmpdir = ffmpeg::tempdir_in(&state.config.tmp_dir).await?;
+    let input = tmpdir.path().join(format!("input{extension}"));
+    stream_to_file(&input, file)
+        .await
+        .context("failed to write audio to split")?;
+
+    let transcription = if ffmpeg::file_duration(&input).await?.as_secs() <= chunk_secs {
+        let file = File::open(&input)
+            .await
+            .context("failed to open audio to transcribe")?;
+        transcribe_with_permit(
+            transcriber,
+            AudioStream::from_file(file),
+            extension,
+            language,
+            model,
+        )
+        .await?
+    } else {
+        let chunk_secs = i32::try_from(chunk_secs).context("chunk length is too large")?;
+        let chunk_dir = tmpdir.path().join("chunks");
+        tokio::fs::create_dir(&chunk_dir)
+            .await
+            .context("failed to create chunks directory")?;
+        let chunks = ffmpeg::split(
+            &input,
+            &chunk_dir,
+            extension,
+            Duration::from_secs(state.config.transcription_chunk_secs),
+        )
+        .await?;
+
+        let mut done: HashMap<i32, DbTranscriptionChunk> =
+            database::get_transcription_chunks(&state.pool, audio_id, chunk_secs, language)
+                .await?
+                .into_iter()
+                .map(|chunk| (chunk.chunk_index, chunk))
+                .collect();
+        let mut texts = Vec::with_capacity(chunks.len());
+        let mut confidences = Vec::with_capacity(chunks.len());
+        // Only known for the chunks transcribed now, saved chunks don't keep it
+        let mut detected_language = None;
+        for (chunk_index, chunk) in chunks.iter().enumerate() {
+            let chunk_index = i32::try_from(chunk_index).context("too many chunks")?;
+            let (text, confidence) = match done.remove(&chunk_index) {
+                Some(chunk) => (chunk.transcription, chunk.confidence),
+                None => {
+                    let file = File::open(chunk)
+                        .await
+                        .context("failed to open audio chunk")?;
+                    let transcription = transcribe_with_permit(
+                        transcriber,
+                        AudioStream::from_file(file),
+                        extension,
+                        language,
+                        model,
+                    )
+                    .await?;
+                    database::upsert_transcription_chunk(
+                        &state.pool,
+                        audio_id,
+                        chunk_index,
+                        chunk_secs,
+                        language,
+                        &transcription.text,
+                        transcription.confidence,
+                    )
+                    .await
+                    .context("failed to save transcription chunk")?;
+                    if detected_language.is_none() {
+                        detected_language = transcription.detected_language;
+                    }
+                    (transcription.text, transcription.confidence)
+                }
+            };
+            texts.push(text.trim().to_string());
+            confidences.extend(confidence);
+        }
+        tracing::info!(
+            audio_id,
+            chunks = chunks.len(),
+            "transcribed audio in chunks"
+        );
+
+        let confidence = (!confidences.is_empty())
+            .then(|| confidences.iter().sum::<f32>() / confidences.len() as f32);
+        Transcription {
+            text: texts
+                .into_iter()
+                .filter(|text| !text.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            confidence,
+            detected_language,
+        }
+    };
+
+    tokio::task::spawn_blocking(move || tmpdir.close())
+        .await?
+        .context("failed to delete tmpdir")?;
+
+    Ok(transcription)
+}
+
+/// Make a title out of the first words of a transcription
+fn suggest_title(transcription: &str) -> Option<String> {
+    let mut title = String::new();
+    for word in transcription.split_whitespace().take(TITLE_MAX_WORDS) {
+        let separator = usize::from(!title.is_empty());
+        if title.chars().count() + separator + word.chars().count() > TITLE_MAX_CHARS {
+            if title.is_empty() {
+                // a single very long word, cut it
+                title = word.chars().take(TITLE_MAX_CHARS).collect();
+            }
+            break;
+        }
+        if separator == 1 {
+            title.push(' ');
+        }
+        title.push_str(word);
+    }
+    (!title.is_empty()).then_some(title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcription_contains(transcription: &str, keyword: &str) -> bool {
+        contains_keyword(&keyword_words(transcription), keyword)
+    }
+
+    #[test]
+    fn keyword_words_ignore_case_and_punctuation() {
+        assert_eq!(
+            keyword_words("Send the Invoice, please!"),
+            ["send", "the", "invoice", "please"]
+        );
+        assert_eq!(keyword_words("  ¿Qué tal?  "), ["qué", "tal"]);
+        assert!(keyword_words("...").is_empty());
+    }
+
+    #[test]
+    fn matches_keywords_ignoring_case_and_punctuation() {
+        assert!(transcription_contains("Send the INVOICE.", "invoice"));
+        assert!(transcription_contains("invoice: paid", "Invoice!"));
+        assert!(!transcription_contains("nothing to see", "invoice"));
+        assert!(!transcription_contains("anything", "..."));
+    }
+
+    #[test]
+    fn matches_whole_words_only() {
+        assert!(!transcription_contains(
+            "it was invoiced yesterday",
+            "invoice"
+        ));
+        assert!(!transcription_contains("reinvoice it", "invoice"));
+    }
+
+    #[test]
+    fn matches_multi_word_keywords_in_order() {
+        assert!(transcription_contains(
+            "call the Dentist, Office now",
+            "dentist office"
+        ));
+        assert!(!transcription_contains(
+            "office of the dentist",
+            "dentist office"
+        ));
+        assert!(!transcription_contains(
+            "dentist and office",
+            "dentist office"
+        ));
+    }
+}
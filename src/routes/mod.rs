@@ -1,6 +1,172 @@
+use std::sync::atomic::Ordering;
+
+use axum::{
+    http::{header::LINK, HeaderMap, HeaderName, HeaderValue, Uri},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, Config, Json};
+
+pub mod admin;
 pub mod audios;
 pub mod users;
 
+/// Total number of items of a paginated response
+pub static X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+/// Page size used by a paginated response, after applying the configured bounds
+pub static X_PAGE_LIMIT: HeaderName = HeaderName::from_static("x-page-limit");
+
+#[derive(Debug, Deserialize)]
+pub struct Pagination {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl Pagination {
+    /// The requested limit bounded by `DEFAULT_PAGE_SIZE` and `MAX_PAGE_SIZE`
+    pub fn limit(&self, config: &Config) -> i64 {
+        self.limit
+            .unwrap_or(config.default_page_size)
+            .clamp(1, config.max_page_size)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+/// Headers of the page of `total` items fetched with `limit` and `offset` by
+/// a request to `uri`, with a `Link` to the next and previous pages if there
+/// are any
+pub fn pagination_headers(uri: &Uri, total: i64, limit: i64, offset: i64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(X_TOTAL_COUNT.clone(), HeaderValue::from(total));
+    headers.insert(X_PAGE_LIMIT.clone(), HeaderValue::from(limit));
+
+    let mut links = Vec::new();
+    let next_offset = offset.saturating_add(limit);
+    if next_offset < total {
+        links.push(page_link(uri, limit, next_offset, "next"));
+    }
+    if offset > 0 {
+        links.push(page_link(
+            uri,
+            limit,
+            offset.saturating_sub(limit).max(0),
+            "prev",
+        ));
+    }
+    if !links.is_empty() {
+        if let Ok(link) = HeaderValue::from_str(&links.join(", ")) {
+            headers.insert(LINK, link);
+        }
+    }
+    headers
+}
+
+/// A `Link` to the page at `offset`, keeping the other query parameters of `uri`
+fn page_link(uri: &Uri, limit: i64, offset: i64, rel: &str) -> String {
+    let mut query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            !pair.is_empty() && key != "limit" && key != "offset"
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if !query.is_empty() {
+        query.push('&');
+    }
+    format!(
+        "<{}?{query}limit={limit}&offset={offset}>; rel=\"{rel}\"",
+        uri.path()
+    )
+}
+
 pub async fn ping() -> &'static str {
     "pong"
 }
+
+#[derive(Serialize)]
+pub struct HealthBody {
+    status: &'static str,
+    stt_available: bool,
+    stt: Option<&'static str>,
+    transcription_permits_available: Option<usize>,
+    /// Writes are rejected while this is on
+    maintenance_mode: bool,
+}
+
+pub async fn health(Extension(state): Extension<AppState>) -> Json<HealthBody> {
+    let transcriber = state.transcriber.get();
+    Json(HealthBody {
+        status: "ok",
+        stt_available: transcriber.is_some(),
+        stt: transcriber.map(|transcriber| transcriber.stt.name()),
+        transcription_permits_available: transcriber
+            .map(|transcriber| transcriber.permits.available_permits()),
+        maintenance_mode: state.maintenance_mode.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(uri: &str, total: i64, limit: i64, offset: i64) -> Option<String> {
+        let uri: Uri = uri.parse().unwrap();
+        pagination_headers(&uri, total, limit, offset)
+            .get(LINK)
+            .map(|link| link.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn links_next_and_prev_pages() {
+        assert_eq!(
+            link("/api/audios", 30, 10, 10).as_deref(),
+            Some(
+                "</api/audios?limit=10&offset=20>; rel=\"next\", \
+                 </api/audios?limit=10&offset=0>; rel=\"prev\""
+            )
+        );
+    }
+
+    #[test]
+    fn links_only_existing_pages() {
+        assert_eq!(
+            link("/api/audios", 30, 10, 0).as_deref(),
+            Some("</api/audios?limit=10&offset=10>; rel=\"next\"")
+        );
+        assert_eq!(
+            link("/api/audios", 30, 10, 20).as_deref(),
+            Some("</api/audios?limit=10&offset=10>; rel=\"prev\"")
+        );
+        assert_eq!(
+            link("/api/audios", 30, 10, 5).as_deref(),
+            Some(
+                "</api/audios?limit=10&offset=15>; rel=\"next\", \
+                 </api/audios?limit=10&offset=0>; rel=\"prev\""
+            )
+        );
+        assert_eq!(link("/api/audios", 10, 10, 0), None);
+    }
+
+    #[test]
+    fn keeps_other_query_params() {
+        assert_eq!(
+            link("/api/audios?q=hello&limit=10&offset=0&sort=asc", 30, 10, 0).as_deref(),
+            Some("</api/audios?q=hello&sort=asc&limit=10&offset=10>; rel=\"next\"")
+        );
+    }
+
+    #[test]
+    fn does_not_overflow_with_large_offsets() {
+        assert_eq!(
+            link("/api/audios", 30, i64::MAX, i64::MAX).as_deref(),
+            Some("</api/audios?limit=9223372036854775807&offset=0>; rel=\"prev\"")
+        );
+    }
+}
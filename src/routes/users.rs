@@ -3,9 +3,10 @@ use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
-use axum::{http::StatusCode, Extension, Json};
+use axum::{http::StatusCode, Extension};
 use chrono::{Duration, Utc};
 use data_encoding::BASE64URL;
+use futures::FutureExt;
 use jsonwebtoken::{encode, Header};
 use lettre::{
     message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
@@ -14,9 +15,11 @@ use lettre::{
 use ring::rand::SecureRandom;
 use serde::{Deserialize, Serialize};
 
-use crate::{database, models::User, ApiError, AppState, Claims, Config};
-
-const TOKEN_BYTES: usize = 48;
+use crate::{
+    database::{self, DbUser, NewAuthEvent},
+    models::User,
+    ApiError, AppState, Claims, ClientInfo, Config, FieldError, Json, Scope, WriteClaims,
+};
 
 #[derive(Deserialize)]
 pub struct AuthPayload {
@@ -32,20 +35,28 @@ pub struct AuthBody {
 
 pub async fn authorize(
     Extension(state): Extension<AppState>,
+    client: ClientInfo,
     Json(payload): Json<AuthPayload>,
 ) -> crate::Result<Json<AuthBody>> {
-    if payload.email.is_empty() || payload.password.is_empty() {
+    let email = database::normalize_email(&payload.email);
+    if email.is_empty() || payload.password.is_empty() {
         return Err(ApiError::BadRequest);
     }
 
-    let user = match database::find_user_by_email(&state.pool, &payload.email).await? {
+    let mut user = match database::find_user_by_email(&state.pool, &email).await? {
         Some(user) => user,
-        None => return Err(ApiError::Unauthorized),
+        None => {
+            log_auth_event(&state, database::AUTH_LOGIN_FAILED, None, &client);
+            return Err(ApiError::Unauthorized);
+        }
     };
 
-    let password_hash = match user.password {
+    let password_hash = match user.password.take() {
         Some(password) => password,
-        None => return Err(ApiError::Unauthorized),
+        None => {
+            log_auth_event(&state, database::AUTH_LOGIN_FAILED, Some(user.id), &client);
+            return Err(ApiError::Unauthorized);
+        }
     };
 
     let parsed_hash =
@@ -55,24 +66,104 @@ pub async fn authorize(
         .is_ok();
 
     if !password_correct {
+        log_auth_event(&state, database::AUTH_LOGIN_FAILED, Some(user.id), &client);
         return Err(ApiError::Unauthorized);
     };
 
+    log_auth_event(
+        &state,
+        database::AUTH_LOGIN_SUCCEEDED,
+        Some(user.id),
+        &client,
+    );
+    Ok(Json(auth_body(&state, user)?))
+}
+
+/// Record an auth event without making the client wait for it
+fn log_auth_event(
+    state: &AppState,
+    event_type: &'static str,
+    user_id: Option<i32>,
+    client: &ClientInfo,
+) {
+    let pool = state.pool.clone();
+    let event = NewAuthEvent {
+        event_type,
+        user_id,
+        ip_address: client.ip.to_string(),
+        user_agent: client.user_agent.clone(),
+    };
+    tokio::spawn(async move {
+        if let Err(err) = database::insert_auth_event(&pool, &event).await {
+            tracing::error!(?err, "failed to insert auth event");
+        }
+    });
+}
+
+fn auth_body(state: &AppState, user: DbUser) -> anyhow::Result<AuthBody> {
+    scoped_auth_body(state, user, Scope::Full)
+}
+
+fn scoped_auth_body(state: &AppState, user: DbUser, scope: Scope) -> anyhow::Result<AuthBody> {
     let expiration_date = Utc::now() + Duration::days(180);
+    let language = token_language(state, &user);
     let claims = Claims {
         user_id: user.id,
         email: user.email,
-        language: user.language,
+        language,
+        avatar_url: user.avatar_url,
+        timezone: user.timezone,
+        scope,
         exp: expiration_date.timestamp(),
     };
 
     let token = encode(&Header::default(), &claims, &state.keys.encoding)
         .context("failed encoding jwt token")?;
 
-    Ok(Json(AuthBody {
+    Ok(AuthBody {
         access_token: token,
         token_type: String::from("Bearer"),
-    }))
+    })
+}
+
+/// The language to put in the user's tokens. If theirs was removed from
+/// `ALLOWED_LANGUAGES` it's the first allowed language, the stored one is kept
+/// until they change it
+fn token_language(state: &AppState, user: &DbUser) -> String {
+    if state.config.is_language_allowed(&user.language) {
+        return user.language.clone();
+    }
+    match state.config.allowed_languages.first() {
+        Some(fallback) => {
+            tracing::warn!(
+                user_id = user.id,
+                language = user.language,
+                fallback,
+                "user language is not allowed, using the first allowed language"
+            );
+            fallback.clone()
+        }
+        None => user.language.clone(),
+    }
+}
+
+/// Issue a token for the user that can read but not change anything, to share
+/// limited access
+pub async fn read_only_token(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    client: ClientInfo,
+) -> crate::Result<Json<AuthBody>> {
+    let user = database::get_user(&state.pool, claims.user_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    log_auth_event(
+        &state,
+        database::AUTH_READ_ONLY_TOKEN_ISSUED,
+        Some(user.id),
+        &client,
+    );
+    Ok(Json(scoped_auth_body(&state, user, Scope::ReadOnly)?))
 }
 
 pub async fn get_user(claims: Claims) -> (StatusCode, Json<User>) {
@@ -81,10 +172,112 @@ pub async fn get_user(claims: Claims) -> (StatusCode, Json<User>) {
         Json(User {
             email: claims.email,
             language: claims.language,
+            avatar_url: claims.avatar_url,
+            timezone: claims.timezone,
+            scope: claims.scope,
         }),
     )
 }
 
+#[derive(Deserialize)]
+pub struct UpdateLanguagePayload {
+    language: String,
+    /// IANA name, like "America/New_York", kept unchanged if missing
+    timezone: Option<String>,
+}
+
+/// Change the language and optionally the timezone of the user, returns a new
+/// token since both are part of the claims
+pub async fn update_language(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    client: ClientInfo,
+    Json(payload): Json<UpdateLanguagePayload>,
+) -> crate::Result<Json<AuthBody>> {
+    if !state.config.is_language_allowed(&payload.language) {
+        return Err(ApiError::BadRequest);
+    }
+    if let Some(timezone) = &payload.timezone {
+        if timezone.parse::<chrono_tz::Tz>().is_err() {
+            return Err(ApiError::ValidationError(vec![FieldError {
+                field: "timezone",
+                message: "must be a known IANA timezone".to_string(),
+            }]));
+        }
+    }
+
+    let mut user = match database::get_user(&state.pool, claims.user_id).await? {
+        Some(user) => user,
+        None => return Err(ApiError::NotFound),
+    };
+
+    database::update_user_language(&state.pool, user.id, &payload.language).await?;
+    user.language = payload.language;
+    if let Some(timezone) = payload.timezone {
+        database::update_user_timezone(&state.pool, user.id, &timezone).await?;
+        user.timezone = Some(timezone);
+    }
+
+    log_auth_event(
+        &state,
+        database::AUTH_TOKEN_REFRESHED,
+        Some(user.id),
+        &client,
+    );
+    Ok(Json(auth_body(&state, user)?))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateAvatarPayload {
+    avatar_url: Option<String>,
+}
+
+/// Avatar urls are stored and sent in every token, so they are kept short
+const AVATAR_URL_MAX_LENGTH: usize = 2048;
+
+/// Change or remove (with null) the avatar url of the user, returns a new token
+/// since the avatar is part of the claims. Only the url is stored, the image is
+/// never fetched.
+pub async fn update_avatar(
+    Extension(state): Extension<AppState>,
+    WriteClaims(claims): WriteClaims,
+    client: ClientInfo,
+    Json(payload): Json<UpdateAvatarPayload>,
+) -> crate::Result<Json<AuthBody>> {
+    if let Some(avatar_url) = &payload.avatar_url {
+        if avatar_url.len() > AVATAR_URL_MAX_LENGTH {
+            return Err(ApiError::ValidationError(vec![FieldError {
+                field: "avatar_url",
+                message: format!("must be at most {AVATAR_URL_MAX_LENGTH} bytes"),
+            }]));
+        }
+        let is_valid = reqwest::Url::parse(avatar_url)
+            .is_ok_and(|url| url.scheme() == "https" && url.host().is_some());
+        if !is_valid {
+            return Err(ApiError::ValidationError(vec![FieldError {
+                field: "avatar_url",
+                message: String::from("must be an absolute https url"),
+            }]));
+        }
+    }
+
+    let mut user = match database::get_user(&state.pool, claims.user_id).await? {
+        Some(user) => user,
+        None => return Err(ApiError::NotFound),
+    };
+
+    database::update_user_avatar(&state.pool, user.id, payload.avatar_url.as_deref()).await?;
+    user.avatar_url = payload.avatar_url;
+
+    log_auth_event(
+        &state,
+        database::AUTH_TOKEN_REFRESHED,
+        Some(user.id),
+        &client,
+    );
+    Ok(Json(auth_body(&state, user)?))
+}
+
 #[derive(Deserialize)]
 pub struct PasswordResetPayload {
     user_id: i32,
@@ -94,17 +287,22 @@ pub struct PasswordResetPayload {
 
 pub async fn password_reset(
     Extension(state): Extension<AppState>,
+    client: ClientInfo,
     Json(payload): Json<PasswordResetPayload>,
 ) -> crate::Result<StatusCode> {
     if payload.new_password.is_empty() {
         return Err(ApiError::BadRequest);
     }
-    let entropy = zxcvbn::zxcvbn(&payload.new_password, &[])
-        .context("failed to check password with zxcvbn")?;
-    if entropy.score() <= 2 {
-        let feedback = entropy.feedback().clone().unwrap();
-        return Err(ApiError::WeakPassword(feedback));
-    }
+    let user = match database::get_user(&state.pool, payload.user_id).await? {
+        Some(user) => user,
+        None => return Err(ApiError::NotFound),
+    };
+    check_new_password(
+        &state.config,
+        "new_password",
+        &payload.new_password,
+        &[&user.email],
+    )?;
     let db_tokens = database::get_user_tokens(&state.pool, payload.user_id).await?;
 
     let mut matched_token = None;
@@ -129,20 +327,28 @@ pub async fn password_reset(
         }
     }
 
-    let user = match database::get_user(&state.pool, payload.user_id).await? {
-        Some(user) => user,
-        None => return Err(ApiError::NotFound),
-    };
-
     if matched_token.is_some() {
         let new_password_hash = hash(&payload.new_password)?;
-        database::update_user_password(&state.pool, payload.user_id, new_password_hash).await?;
-        database::delete_user_tokens(&state.pool, payload.user_id).await?;
+        let user_id = payload.user_id;
+        database::transaction(&state.pool, move |conn| {
+            async move {
+                database::update_user_password(&mut *conn, user_id, new_password_hash).await?;
+                database::delete_user_tokens(conn, user_id).await
+            }
+            .boxed()
+        })
+        .await?;
+        log_auth_event(
+            &state,
+            database::AUTH_PASSWORD_RESET_COMPLETED,
+            Some(user.id),
+            &client,
+        );
 
         tokio::spawn(async move {
             let email_body = "Your password has been updated successfully.";
             let subject = "Password updated";
-            match send_email(&state.config, subject, email_body.to_string(), &user.email).await {
+            match send_email(&state, subject, email_body.to_string(), &user.email).await {
                 Ok(()) => {}
                 Err(err) => tracing::error!(?err, "error sending email"),
             };
@@ -160,6 +366,7 @@ pub struct RequestPasswordResetPayload {
 
 pub async fn request_password_reset(
     Extension(state): Extension<AppState>,
+    client: ClientInfo,
     Json(payload): Json<RequestPasswordResetPayload>,
 ) -> crate::Result<(StatusCode, &'static str)> {
     let response = Ok((
@@ -167,29 +374,53 @@ pub async fn request_password_reset(
         "If that email address is in our database, we will send you an email to reset your password."
     ));
 
-    let token = generate_token(&state.rand_rng)?;
+    let token = generate_token(&state.rand_rng, state.config.reset_token_bytes)?;
     let token_hash = hash(&token)?;
 
-    let link = &state.config.password_reset_link;
-
     let user = match database::find_user_by_email(&state.pool, &payload.email).await? {
         Some(user) => user,
-        None => return response,
+        None => {
+            log_auth_event(
+                &state,
+                database::AUTH_PASSWORD_RESET_REQUESTED,
+                None,
+                &client,
+            );
+            return response;
+        }
     };
+    log_auth_event(
+        &state,
+        database::AUTH_PASSWORD_RESET_REQUESTED,
+        Some(user.id),
+        &client,
+    );
+    if !state.config.is_language_allowed(&user.language) {
+        // Still sent, the token issued after the reset gets an allowed language
+        tracing::warn!(
+            user_id = user.id,
+            language = user.language,
+            "user requesting a password reset has a language that is not allowed"
+        );
+    }
 
     database::insert_token(&state.pool, user.id, token_hash).await?;
 
+    let link = state
+        .config
+        .password_reset_link
+        .replace(crate::RESET_LINK_TOKEN, &token)
+        .replace(crate::RESET_LINK_USER_ID, &user.id.to_string());
     let email_body = format!(
         r#"
-Follow this link for resetting your password: {}?token={}&user_id={}
+Follow this link for resetting your password: {link}
 
-If you didn't initialize any password reset, you can safely ignore this message."#,
-        link, token, user.id
+If you didn't initialize any password reset, you can safely ignore this message."#
     );
 
     tokio::spawn(async move {
         let subject = "Password reset link";
-        match send_email(&state.config, subject, email_body, &user.email).await {
+        match send_email(&state, subject, email_body, &user.email).await {
             Ok(()) => {}
             Err(err) => tracing::error!(?err, "error sending email"),
         };
@@ -198,7 +429,96 @@ If you didn't initialize any password reset, you can safely ignore this message.
     response
 }
 
-fn hash(password: &str) -> anyhow::Result<String> {
+/// Lowest zxcvbn score of a password to be accepted
+const MIN_PASSWORD_SCORE: u8 = 3;
+
+/// Check that `password` is long and strong enough to be used as a password.
+/// `user_inputs` are words the password should not be based on, like the
+/// user's email
+pub(crate) fn check_new_password(
+    config: &Config,
+    field: &'static str,
+    password: &str,
+    user_inputs: &[&str],
+) -> crate::Result<()> {
+    // zxcvbn can score short passwords with special characters highly
+    if password.chars().count() < config.password_min_length {
+        return Err(ApiError::ValidationError(vec![FieldError {
+            field,
+            message: format!("must be at least {} characters", config.password_min_length),
+        }]));
+    }
+
+    let entropy = password_entropy(password, user_inputs)?;
+    if entropy.score() < MIN_PASSWORD_SCORE {
+        let feedback = entropy.feedback().clone().unwrap_or_default();
+        return Err(ApiError::WeakPassword(feedback));
+    }
+
+    Ok(())
+}
+
+fn password_entropy(password: &str, user_inputs: &[&str]) -> anyhow::Result<zxcvbn::Entropy> {
+    zxcvbn::zxcvbn(password, user_inputs).context("failed to check password with zxcvbn")
+}
+
+#[derive(Deserialize)]
+pub struct CheckPasswordPayload {
+    password: String,
+    /// Email of an account that doesn't exist yet, taken into account like the
+    /// email of a signed in user
+    email: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CheckPasswordBody {
+    /// zxcvbn score, from 0 to 4
+    score: u8,
+    /// Whether `password_reset` would accept the password
+    accepted: bool,
+    min_length: usize,
+    warning: Option<String>,
+    suggestions: Vec<String>,
+}
+
+/// Evaluate a password the same way `password_reset` does, without changing
+/// anything. The email of the signed in user is taken into account, or the one
+/// in the payload when there is no account yet
+pub async fn check_password(
+    Extension(state): Extension<AppState>,
+    claims: Option<Claims>,
+    Json(payload): Json<CheckPasswordPayload>,
+) -> crate::Result<Json<CheckPasswordBody>> {
+    if payload.password.is_empty() {
+        return Err(ApiError::BadRequest);
+    }
+    let email = claims.map(|claims| claims.email).or(payload.email);
+    let user_inputs: Vec<&str> = email.as_deref().into_iter().collect();
+
+    let min_length = state.config.password_min_length;
+    let entropy = password_entropy(&payload.password, &user_inputs)?;
+    let score = entropy.score();
+    let feedback = entropy.feedback().as_ref();
+    Ok(Json(CheckPasswordBody {
+        score,
+        accepted: payload.password.chars().count() >= min_length && score >= MIN_PASSWORD_SCORE,
+        min_length,
+        warning: feedback
+            .and_then(|feedback| feedback.warning())
+            .map(|warning| warning.to_string()),
+        suggestions: feedback
+            .map(|feedback| {
+                feedback
+                    .suggestions()
+                    .iter()
+                    .map(|suggestion| suggestion.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }))
+}
+
+pub(crate) fn hash(password: &str) -> anyhow::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
     Ok(Argon2::default()
         .hash_password(password.as_bytes(), &salt)
@@ -206,14 +526,34 @@ fn hash(password: &str) -> anyhow::Result<String> {
         .to_string())
 }
 
-fn generate_token(rng: &dyn SecureRandom) -> anyhow::Result<String> {
-    let mut random = [0u8; TOKEN_BYTES];
+pub(crate) fn generate_token(rng: &dyn SecureRandom, bytes: usize) -> anyhow::Result<String> {
+    let mut random = vec![0u8; bytes];
     rng.fill(&mut random)?;
 
     Ok(BASE64URL.encode(&random))
 }
 
+/// Send an email and record whether it was delivered in the `email_log` table
 async fn send_email(
+    state: &AppState,
+    subject: &str,
+    body: String,
+    user_email: &str,
+) -> anyhow::Result<()> {
+    let result = deliver_email(&state.config, subject, body, user_email).await;
+    let (status, error) = match &result {
+        Ok(()) => (database::EMAIL_SENT, None),
+        Err(err) => (database::EMAIL_FAILED, Some(format!("{err:#}"))),
+    };
+    if let Err(err) =
+        database::insert_email_log(&state.pool, user_email, subject, status, error.as_deref()).await
+    {
+        tracing::error!(?err, "failed to record email in email log");
+    }
+    result
+}
+
+async fn deliver_email(
     config: &Config,
     subject: &str,
     body: String,
@@ -227,7 +567,7 @@ async fn send_email(
     };
 
     let email = Message::builder()
-        .from(config.smtp_from.parse().unwrap())
+        .from(config.smtp_from.clone())
         .to(to_mbox)
         .subject(subject)
         .header(ContentType::TEXT_PLAIN)
@@ -236,16 +576,35 @@ async fn send_email(
 
     let creds = Credentials::new(config.smtp_username.clone(), config.smtp_password.clone());
 
-    let mailer: AsyncSmtpTransport<Tokio1Executor> =
+    let builder = if config.smtp_starttls {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_relay)
+    } else {
         AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_relay)
-            .unwrap()
-            .credentials(creds)
-            .build();
-
-    match mailer.send(email).await {
-        Ok(_) => {}
-        Err(err) => anyhow::bail!("mailer.send(email) error: {}", err),
     };
+    let mailer: AsyncSmtpTransport<Tokio1Executor> = builder
+        .context("failed to create smtp transport")?
+        .port(config.smtp_port)
+        .credentials(creds)
+        .build();
+
+    let mut backoff = std::time::Duration::from_millis(config.smtp_retry_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        match mailer.send(email.clone()).await {
+            Ok(_) => break,
+            Err(err) if attempt < config.smtp_send_attempts && !err.is_permanent() => {
+                tracing::warn!(
+                    ?err,
+                    attempt,
+                    "failed to send email, retrying in {backoff:?}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => anyhow::bail!("mailer.send(email) error after {attempt} attempts: {err}"),
+        }
+    }
 
     Ok(())
 }
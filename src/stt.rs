@@ -1,103 +1,579 @@
 use std::{
+    collections::HashMap,
     io,
     path::{Path, PathBuf},
-    process::Stdio,
+    sync::{Arc, Mutex, MutexGuard, TryLockError},
+    time::Duration,
 };
 
 use anyhow::Context;
 use axum::async_trait;
 use futures::StreamExt;
-use leopard::LeopardBuilder;
+use leopard::{Leopard, LeopardBuilder};
 use reqwest::{
+    header::RETRY_AFTER,
     multipart::{Form, Part},
-    Client,
+    Client, StatusCode,
 };
 use serde::Deserialize;
-use tempfile::TempDir;
-use tokio::{fs::File, io::BufWriter, process::Command};
+use tokio::{
+    fs::File,
+    io::BufWriter,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
 use tokio_util::io::StreamReader;
 use tracing::instrument;
 
-use crate::audio_storage::{stream_to_file, AudioStream, AUDIO_FILE_EXTENSION};
+use crate::{
+    audio_storage::{stream_to_file, AudioStream},
+    ffmpeg,
+};
+
+/// Languages supported by the whisper model
+const WHISPER_LANGUAGES: &[&str] = &[
+    "af", "am", "ar", "as", "az", "ba", "be", "bg", "bn", "bo", "br", "bs", "ca", "cs", "cy", "da",
+    "de", "el", "en", "es", "et", "eu", "fa", "fi", "fo", "fr", "gl", "gu", "ha", "he", "hi", "hr",
+    "ht", "hu", "hy", "id", "is", "it", "ja", "jw", "ka", "kk", "km", "kn", "ko", "la", "lb", "ln",
+    "lo", "lt", "lv", "mg", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "ne", "nl", "nn", "no",
+    "oc", "pa", "pl", "ps", "pt", "ro", "ru", "sa", "sd", "si", "sk", "sl", "sn", "so", "sq", "sr",
+    "su", "sv", "sw", "ta", "te", "tg", "th", "tk", "tl", "tr", "tt", "uk", "ur", "uz", "vi", "yi",
+    "yo", "zh",
+];
+
+/// Names of `WHISPER_LANGUAGES` in whisper responses
+const WHISPER_LANGUAGE_NAMES: &[(&str, &str)] = &[
+    ("af", "afrikaans"),
+    ("am", "amharic"),
+    ("ar", "arabic"),
+    ("as", "assamese"),
+    ("az", "azerbaijani"),
+    ("ba", "bashkir"),
+    ("be", "belarusian"),
+    ("bg", "bulgarian"),
+    ("bn", "bengali"),
+    ("bo", "tibetan"),
+    ("br", "breton"),
+    ("bs", "bosnian"),
+    ("ca", "catalan"),
+    ("cs", "czech"),
+    ("cy", "welsh"),
+    ("da", "danish"),
+    ("de", "german"),
+    ("el", "greek"),
+    ("en", "english"),
+    ("es", "spanish"),
+    ("et", "estonian"),
+    ("eu", "basque"),
+    ("fa", "persian"),
+    ("fi", "finnish"),
+    ("fo", "faroese"),
+    ("fr", "french"),
+    ("gl", "galician"),
+    ("gu", "gujarati"),
+    ("ha", "hausa"),
+    ("he", "hebrew"),
+    ("hi", "hindi"),
+    ("hr", "croatian"),
+    ("ht", "haitian creole"),
+    ("hu", "hungarian"),
+    ("hy", "armenian"),
+    ("id", "indonesian"),
+    ("is", "icelandic"),
+    ("it", "italian"),
+    ("ja", "japanese"),
+    ("jw", "javanese"),
+    ("ka", "georgian"),
+    ("kk", "kazakh"),
+    ("km", "khmer"),
+    ("kn", "kannada"),
+    ("ko", "korean"),
+    ("la", "latin"),
+    ("lb", "luxembourgish"),
+    ("ln", "lingala"),
+    ("lo", "lao"),
+    ("lt", "lithuanian"),
+    ("lv", "latvian"),
+    ("mg", "malagasy"),
+    ("mi", "maori"),
+    ("mk", "macedonian"),
+    ("ml", "malayalam"),
+    ("mn", "mongolian"),
+    ("mr", "marathi"),
+    ("ms", "malay"),
+    ("mt", "maltese"),
+    ("my", "myanmar"),
+    ("ne", "nepali"),
+    ("nl", "dutch"),
+    ("nn", "nynorsk"),
+    ("no", "norwegian"),
+    ("oc", "occitan"),
+    ("pa", "punjabi"),
+    ("pl", "polish"),
+    ("ps", "pashto"),
+    ("pt", "portuguese"),
+    ("ro", "romanian"),
+    ("ru", "russian"),
+    ("sa", "sanskrit"),
+    ("sd", "sindhi"),
+    ("si", "sinhala"),
+    ("sk", "slovak"),
+    ("sl", "slovenian"),
+    ("sn", "shona"),
+    ("so", "somali"),
+    ("sq", "albanian"),
+    ("sr", "serbian"),
+    ("su", "sundanese"),
+    ("sv", "swedish"),
+    ("sw", "swahili"),
+    ("ta", "tamil"),
+    ("te", "telugu"),
+    ("tg", "tajik"),
+    ("th", "thai"),
+    ("tk", "turkmen"),
+    ("tl", "tagalog"),
+    ("tr", "turkish"),
+    ("tt", "tatar"),
+    ("uk", "ukrainian"),
+    ("ur", "urdu"),
+    ("uz", "uzbek"),
+    ("vi", "vietnamese"),
+    ("yi", "yiddish"),
+    ("yo", "yoruba"),
+    ("zh", "chinese"),
+];
+
+/// Languages picovoice leopard provides models for
+const PICOVOICE_LEOPARD_LANGUAGES: &[&str] = &["de", "en", "es", "fr", "it", "ja", "ko", "pt"];
+
+/// Seconds to wait before retrying when a rate limited response has no usable `Retry-After`
+const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+pub struct Transcription {
+    pub text: String,
+    /// How likely the text is to be right, from 0 to 1, None if the provider
+    /// doesn't report it
+    pub confidence: Option<f32>,
+    /// ISO 639-1 code of the spoken language, None if the provider doesn't
+    /// detect it
+    pub detected_language: Option<String>,
+}
+
+/// What to do when a provider returns a transcription with no words, as
+/// whisper does for silent audios
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmptyTranscription {
+    /// Save it like any other transcription
+    Keep,
+    /// Treat it as a failed transcription, so it is retried
+    Fail,
+    /// Save the audio with the `empty` status instead of a transcription
+    Mark,
+}
 
 #[async_trait]
 pub trait SpeechToText {
-    async fn transcribe(&self, file: AudioStream, language: &str) -> anyhow::Result<String>;
+    /// Transcribe an audio, `extension` is the file extension of its format.
+    /// `model` overrides the default model of providers that have several,
+    /// others ignore it
+    async fn transcribe(
+        &self,
+        file: AudioStream,
+        extension: &str,
+        language: &str,
+        model: Option<&str>,
+    ) -> Result<Transcription, TranscriptionError>;
+
+    /// Whether `language` (an ISO 639-1 code) can be transcribed
+    fn supports_language(&self, language: &str) -> bool;
+
+    /// Name of the provider, recorded as the source of its transcriptions
+    fn name(&self) -> &'static str;
+}
+
+/// Changes a transcription after the provider produced it and before it is
+/// saved, processors are applied in order
+pub trait TranscriptionPostProcessor {
+    fn process(&self, transcription: String, language: &str) -> anyhow::Result<String>;
+}
+
+/// Trim surrounding whitespace and capitalize the first letter
+pub struct TrimAndCapitalize;
+
+impl TranscriptionPostProcessor for TrimAndCapitalize {
+    fn process(&self, transcription: String, _language: &str) -> anyhow::Result<String> {
+        let trimmed = transcription.trim();
+        let mut chars = trimmed.chars();
+        Ok(match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        })
+    }
+}
+
+/// Remove the filler words of the transcription's language, compared ignoring
+/// case and surrounding punctuation. The whitespace between the remaining words
+/// is kept, preferring line breaks where words were removed
+pub struct RemoveFiller {
+    /// Filler words of each language
+    words: HashMap<String, Vec<String>>,
+}
+
+impl RemoveFiller {
+    /// `words` are pairs of language and filler word
+    pub fn new(words: Vec<(String, String)>) -> RemoveFiller {
+        let mut by_language: HashMap<String, Vec<String>> = HashMap::new();
+        for (language, word) in words {
+            by_language
+                .entry(language.to_lowercase())
+                .or_default()
+                .push(word.to_lowercase());
+        }
+        RemoveFiller { words: by_language }
+    }
+}
+
+fn is_filler(words: &[String], word: &str) -> bool {
+    let word = word
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase();
+    words.contains(&word)
+}
+
+/// Split `text` into runs of whitespace and runs of anything else
+fn whitespace_runs(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let end = rest
+            .find(|c: char| c.is_whitespace() != first.is_whitespace())
+            .unwrap_or(rest.len());
+        let (run, remaining) = rest.split_at(end);
+        rest = remaining;
+        Some(run)
+    })
+}
+
+impl TranscriptionPostProcessor for RemoveFiller {
+    fn process(&self, transcription: String, language: &str) -> anyhow::Result<String> {
+        let Some(words) = self.words.get(language) else {
+            return Ok(transcription);
+        };
+
+        let mut result = String::with_capacity(transcription.len());
+        // Whitespace to write before the next word that is kept
+        let mut gap: Option<&str> = None;
+        let mut trailing = "";
+        let mut removed_first_word = false;
+        for run in whitespace_runs(&transcription) {
+            if run.starts_with(char::is_whitespace) {
+                trailing = run;
+                // A removed first word shouldn't leave a space at the start
+                if removed_first_word && result.is_empty() {
+                    continue;
+                }
+                gap = match gap {
+                    Some(previous) if previous.contains('\n') || !run.contains('\n') => {
+                        Some(previous)
+                    }
+                    _ => Some(run),
+                };
+                continue;
+            }
+
+            trailing = "";
+            if is_filler(words, run) {
+                removed_first_word |= result.is_empty();
+                continue;
+            }
+            result.push_str(gap.unwrap_or_default());
+            result.push_str(run);
+            gap = None;
+        }
+        result.push_str(trailing);
+        Ok(result)
+    }
+}
+
+#[derive(Debug)]
+pub enum TranscriptionError {
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    /// The provider returned a server error
+    ServiceUnavailable,
+    /// The provider returned a body we don't understand
+    UnexpectedResponse(String),
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for TranscriptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscriptionError::RateLimited { retry_after_secs } => write!(
+                f,
+                "rate limited by speech to text provider, retry after {retry_after_secs}s"
+            ),
+            TranscriptionError::ServiceUnavailable => {
+                write!(f, "speech to text provider is unavailable")
+            }
+            TranscriptionError::UnexpectedResponse(body) => {
+                write!(
+                    f,
+                    "unexpected response from speech to text provider: {body}"
+                )
+            }
+            // The error itself is the source, so chains don't repeat it
+            TranscriptionError::Other(_) => write!(f, "failed to transcribe audio"),
+        }
+    }
+}
+
+impl std::error::Error for TranscriptionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TranscriptionError::RateLimited { .. }
+            | TranscriptionError::ServiceUnavailable
+            | TranscriptionError::UnexpectedResponse(_) => None,
+            TranscriptionError::Other(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for TranscriptionError {
+    fn from(err: anyhow::Error) -> Self {
+        TranscriptionError::Other(err)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct WhisperApi {
     client: Client,
     openai_api_key: String,
+    /// Sent as `OpenAI-Organization` to bill requests to that organization
+    openai_organization: Option<String>,
+    /// Used for audios without a model of their own
+    default_model: String,
+    /// Let whisper detect the language instead of sending the audio's, so
+    /// the detected language can differ from it
+    detect_language: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct PicovoiceLeopard<'a> {
+    client: Client,
     access_key: String,
     models_folder: &'a Path,
     library_path: PathBuf,
+    tmp_dir: PathBuf,
+    instances_per_language: usize,
+    /// Initialized lazily for languages that weren't given to `new_with_languages`
+    instances: Arc<tokio::sync::Mutex<HashMap<String, LeopardInstances>>>,
+}
+
+/// Leopard instances of one language, each one used by a single transcription
+/// at a time
+#[derive(Clone)]
+struct LeopardInstances {
+    instances: Arc<Vec<Mutex<Leopard>>>,
+    /// One permit per instance, so transcriptions wait for whichever instance
+    /// is freed first
+    permits: Arc<Semaphore>,
 }
 
+impl std::fmt::Debug for LeopardInstances {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LeopardInstances({})", self.instances.len())
+    }
+}
+
+impl LeopardInstances {
+    fn new(instances: Vec<Mutex<Leopard>>) -> LeopardInstances {
+        LeopardInstances {
+            permits: Arc::new(Semaphore::new(instances.len())),
+            instances: Arc::new(instances),
+        }
+    }
+
+    /// Wait until an instance is free, the permit is needed to lock it
+    async fn acquire(&self) -> anyhow::Result<OwnedSemaphorePermit> {
+        Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .context("leopard instances were closed")
+    }
+
+    /// Lock a free instance, there is one for every permit
+    fn lock(&self, _permit: &OwnedSemaphorePermit) -> MutexGuard<'_, Leopard> {
+        self.instances
+            .iter()
+            .find_map(|instance| match instance.try_lock() {
+                Ok(leopard) => Some(leopard),
+                // A panic while processing doesn't leave the instance unusable
+                Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+                Err(TryLockError::WouldBlock) => None,
+            })
+            .expect("an instance is free while a permit is held")
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Clone)]
 pub struct SpeechToTextMock;
 
+/// Build the client used for requests to speech to text providers
+pub fn http_client(connect_timeout: Duration, timeout: Duration) -> anyhow::Result<Client> {
+    Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .build()
+        .context("failed to build http client")
+}
+
 impl WhisperApi {
-    pub fn new(openai_api_key: String) -> Self {
-        let client = Client::new();
+    pub fn new(
+        openai_api_key: String,
+        openai_organization: Option<String>,
+        client: Client,
+        default_model: String,
+        detect_language: bool,
+    ) -> Self {
         Self {
             client,
             openai_api_key,
+            openai_organization,
+            default_model,
+            detect_language,
+        }
+    }
+
+    /// List the models available to the api key to check that it works,
+    /// without paying for a transcription
+    pub async fn check(&self) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .get("https://api.openai.com/v1/models")
+            .bearer_auth(&self.openai_api_key);
+        if let Some(organization) = &self.openai_organization {
+            request = request.header("OpenAI-Organization", organization);
         }
+        request
+            .send()
+            .await
+            .context("failed to send request to openai api")?
+            .error_for_status()
+            .context("openai api rejected the request")?;
+        Ok(())
     }
 }
 
 #[async_trait]
 impl SpeechToText for WhisperApi {
     #[instrument]
-    async fn transcribe(&self, stream: AudioStream, language: &str) -> anyhow::Result<String> {
+    async fn transcribe(
+        &self,
+        stream: AudioStream,
+        extension: &str,
+        language: &str,
+        model: Option<&str>,
+    ) -> Result<Transcription, TranscriptionError> {
         // TODO: use reqwest::Body::wrap_stream instead
         // The reason I am currently doing this is that Pageable<GetBlobResponse, azure_core::Error>
         // is not Sync, so I can't make AudioStream Sync, and that means I can't pass it to wrap_stream
         let bytes = stream.into_bytes().await?;
-        let length = bytes.len().try_into()?;
+        let length = bytes
+            .len()
+            .try_into()
+            .context("audio is too large to send to whisper api")?;
         let body = reqwest::Body::from(bytes);
-        let file_part = Part::stream_with_length(body, length)
-            .file_name(format!("audio{}", AUDIO_FILE_EXTENSION));
-        let form = Form::new()
+        let file_part =
+            Part::stream_with_length(body, length).file_name(format!("audio{extension}"));
+        let mut form = Form::new()
             .part("file", file_part)
-            .text("model", "whisper-1")
-            .text("language", language.to_string());
+            .text("model", model.unwrap_or(&self.default_model).to_string())
+            .text("response_format", "verbose_json");
+        if !self.detect_language {
+            form = form.text("language", language.to_string());
+        }
 
-        let res: WhisperApiResponse = self
+        let mut request = self
             .client
             .post("https://api.openai.com/v1/audio/transcriptions")
-            .bearer_auth(&self.openai_api_key)
+            .bearer_auth(&self.openai_api_key);
+        if let Some(organization) = &self.openai_organization {
+            request = request.header("OpenAI-Organization", organization);
+        }
+        let response = request
             .multipart(form)
             .send()
-            .await?
-            .json()
-            .await?;
+            .await
+            .context("failed to send request to whisper api")?;
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after_secs = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+            return Err(TranscriptionError::RateLimited { retry_after_secs });
+        }
+
+        if response.status().is_server_error() {
+            tracing::warn!(status = %response.status(), "whisper api returned a server error");
+            return Err(TranscriptionError::ServiceUnavailable);
+        }
+
+        let body = response
+            .text()
+            .await
+            .context("failed to read whisper api response")?;
+        let res: WhisperApiResponse = match serde_json::from_str(&body) {
+            Ok(res) => res,
+            Err(_) => return Err(TranscriptionError::UnexpectedResponse(body)),
+        };
 
         if let Some(text) = res.text {
-            return Ok(text);
+            let confidence = res.segments.as_deref().and_then(whisper_confidence);
+            let detected_language = res
+                .language
+                .as_deref()
+                .and_then(whisper_language_code)
+                .map(ToString::to_string);
+            return Ok(Transcription {
+                text,
+                confidence,
+                detected_language,
+            });
         }
 
         if let Some(error) = res.error {
-            return Err(anyhow::anyhow!(
-                "error returned from whisper api: {}",
-                error
-            ));
+            return Err(anyhow::anyhow!("error returned from whisper api: {}", error).into());
         }
 
-        return Err(anyhow::anyhow!("whisper api did not return text nor error"));
+        Err(TranscriptionError::UnexpectedResponse(body))
+    }
+
+    fn supports_language(&self, language: &str) -> bool {
+        WHISPER_LANGUAGES.contains(&language)
+    }
+
+    fn name(&self) -> &'static str {
+        "whisper"
     }
 }
 
 impl<'a> PicovoiceLeopard<'a> {
     #[instrument]
+    /// Audios are written to temporary directories inside `tmp_dir` to be
+    /// transcribed. `instances_per_language` Leopard instances are kept for
+    /// each language, so that many audios of a language can be transcribed at
+    /// the same time.
     pub async fn new_with_languages(
         languages: &'a [&'a str],
         access_key: String,
+        client: Client,
+        tmp_dir: PathBuf,
+        instances_per_language: usize,
     ) -> anyhow::Result<PicovoiceLeopard<'a>> {
         let models_folder = Path::new("picovoice_leopard_models");
         if !models_folder.exists() {
@@ -106,25 +582,64 @@ impl<'a> PicovoiceLeopard<'a> {
 
         for language in languages {
             if !models_folder.join(language).is_file() {
-                PicovoiceLeopard::download_model(models_folder, language).await?;
+                PicovoiceLeopard::download_model(&client, models_folder, language).await?;
             }
         }
 
         let current_dir = std::env::current_dir().context("failed to get current dir")?;
         let library_path = current_dir.join("picovoice_leopard_lib.so");
         if !library_path.exists() {
-            PicovoiceLeopard::download_library(&library_path).await?;
+            PicovoiceLeopard::download_library(&client, &library_path).await?;
         }
 
-        Ok(PicovoiceLeopard {
+        let leopard = PicovoiceLeopard {
+            client,
             access_key,
             models_folder,
             library_path,
+            tmp_dir,
+            instances_per_language,
+            instances: Default::default(),
+        };
+        for language in languages {
+            leopard.get_instances(language).await?;
+        }
+        Ok(leopard)
+    }
+
+    /// The instances of `language`, initializing them on first use
+    async fn get_instances(&self, language: &str) -> anyhow::Result<LeopardInstances> {
+        let mut instances = self.instances.lock().await;
+        if let Some(language_instances) = instances.get(language) {
+            return Ok(language_instances.clone());
+        }
+
+        let model_path = self.get_model_path(language).await?;
+        let access_key = self.access_key.clone();
+        let library_path = self.library_path.to_owned();
+        let count = self.instances_per_language;
+        let language_instances = tokio::task::spawn_blocking(move || {
+            (0..count)
+                .map(|_| {
+                    LeopardBuilder::new()
+                        .access_key(&access_key)
+                        .model_path(&model_path)
+                        .library_path(&library_path)
+                        .init()
+                        .map(Mutex::new)
+                        .context("failed LeopardBuilder init")
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
         })
+        .await??;
+
+        let language_instances = LeopardInstances::new(language_instances);
+        instances.insert(language.to_string(), language_instances.clone());
+        Ok(language_instances)
     }
 
     #[instrument]
-    async fn download_model(folder: &Path, language: &str) -> anyhow::Result<()> {
+    async fn download_model(client: &Client, folder: &Path, language: &str) -> anyhow::Result<()> {
         let base_url = "https://github.com/Picovoice/leopard/raw/master/lib/common/leopard_params";
         let url = if language == "en" {
             format!("{base_url}.pv")
@@ -133,7 +648,7 @@ impl<'a> PicovoiceLeopard<'a> {
         };
 
         tracing::info!("fetching picovoice leopard model for language: {language}");
-        let stream = reqwest::get(url).await?.bytes_stream();
+        let stream = client.get(url).send().await?.bytes_stream();
         let path = folder.join(language);
         stream_to_file(&path, stream).await?;
 
@@ -141,11 +656,11 @@ impl<'a> PicovoiceLeopard<'a> {
     }
 
     #[instrument]
-    async fn download_library(path: &Path) -> anyhow::Result<()> {
+    async fn download_library(client: &Client, path: &Path) -> anyhow::Result<()> {
         let url =
             "https://github.com/Picovoice/leopard/raw/master/lib/linux/x86_64/libpv_leopard.so";
         tracing::info!("fetching picovoice library");
-        let stream = reqwest::get(url).await?.bytes_stream();
+        let stream = client.get(url).send().await?.bytes_stream();
         stream_to_file(path, stream).await?;
         Ok(())
     }
@@ -154,7 +669,7 @@ impl<'a> PicovoiceLeopard<'a> {
     async fn get_model_path(&self, language: &str) -> anyhow::Result<PathBuf> {
         let path = self.models_folder.join(language);
         if !path.exists() {
-            PicovoiceLeopard::download_model(self.models_folder, language).await?;
+            PicovoiceLeopard::download_model(&self.client, self.models_folder, language).await?;
         }
         Ok(path)
     }
@@ -163,17 +678,42 @@ impl<'a> PicovoiceLeopard<'a> {
 #[async_trait]
 impl<'a> SpeechToText for PicovoiceLeopard<'a> {
     #[instrument]
-    async fn transcribe(&self, stream: AudioStream, language: &str) -> anyhow::Result<String> {
-        let model_path = self.get_model_path(language).await?;
+    async fn transcribe(
+        &self,
+        stream: AudioStream,
+        extension: &str,
+        language: &str,
+        _model: Option<&str>,
+    ) -> Result<Transcription, TranscriptionError> {
+        Ok(self.transcribe_file(stream, extension, language).await?)
+    }
+
+    fn supports_language(&self, language: &str) -> bool {
+        PICOVOICE_LEOPARD_LANGUAGES.contains(&language)
+    }
 
-        let tmpdir = tokio::task::spawn_blocking(TempDir::new).await??;
-        let path = tmpdir.path().join(format!("audio{}", AUDIO_FILE_EXTENSION));
+    fn name(&self) -> &'static str {
+        "picovoice_leopard"
+    }
+}
+
+impl<'a> PicovoiceLeopard<'a> {
+    async fn transcribe_file(
+        &self,
+        stream: AudioStream,
+        extension: &str,
+        language: &str,
+    ) -> anyhow::Result<Transcription> {
+        let instances = self.get_instances(language).await?;
+
+        let tmpdir = ffmpeg::tempdir_in(&self.tmp_dir).await?;
+        let path = tmpdir.path().join(format!("audio{extension}"));
         let mut file = File::create(&path)
             .await
             .context("failed to create file in tmpdir")?;
         let mut writer = BufWriter::new(&mut file);
 
-        let stream = stream.map(|v| v.map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+        let stream = stream.map(|v| v.map_err(io::Error::other));
         let mut reader = StreamReader::new(stream);
 
         tokio::io::copy(&mut reader, &mut writer).await?;
@@ -182,34 +722,13 @@ impl<'a> SpeechToText for PicovoiceLeopard<'a> {
         // trying to transcribe those audios with this api produces an error.
         // To fix this, repackage the files with ffmpeg.
         // See https://stackoverflow.com/a/40117749
-        let new_path = tmpdir
-            .path()
-            .join(format!("new_audio{}", AUDIO_FILE_EXTENSION));
-        let exit_status = Command::new("ffmpeg")
-            .arg("-i")
-            .arg(&path)
-            .arg("-acodec")
-            .arg("copy")
-            .arg(&new_path)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .await
-            .context("failed executing ffmpeg")?;
-        if !exit_status.success() {
-            anyhow::bail!("ffmpeg exited with non-successful exit status: {exit_status}");
-        }
+        let new_path = tmpdir.path().join(format!("new_audio{extension}"));
+        ffmpeg::convert(&path, &new_path, &["-acodec", "copy"]).await?;
 
-        let access_key = self.access_key.clone();
-        let library_path = self.library_path.to_owned();
+        let permit = instances.acquire().await?;
         let transcript = tokio::task::spawn_blocking(move || {
-            let leopard = LeopardBuilder::new()
-                .access_key(&access_key)
-                .model_path(model_path)
-                .library_path(library_path)
-                .init()
-                .context("failed LeopardBuilder init")?;
-            let transcript = leopard
+            let transcript = instances
+                .lock(&permit)
                 .process_file(&new_path)
                 .context("failed to process file")?;
 
@@ -221,20 +740,125 @@ impl<'a> SpeechToText for PicovoiceLeopard<'a> {
             .await?
             .context("failed to delete tmpdir")?;
 
-        Ok(transcript.transcript)
+        let confidence = (!transcript.words.is_empty()).then(|| {
+            let total: f32 = transcript.words.iter().map(|w| w.confidence).sum();
+            total / transcript.words.len() as f32
+        });
+        Ok(Transcription {
+            text: transcript.transcript,
+            confidence,
+            detected_language: None,
+        })
     }
 }
 
 #[derive(Deserialize)]
 struct WhisperApiResponse {
     text: Option<String>,
+    segments: Option<Vec<WhisperSegment>>,
+    /// Name of the language in english, like "spanish"
+    language: Option<String>,
     error: Option<serde_json::Value>,
 }
 
+#[derive(Deserialize)]
+struct WhisperSegment {
+    avg_logprob: f32,
+}
+
+/// The code of a language as whisper names it in `verbose_json` responses
+fn whisper_language_code(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    WHISPER_LANGUAGE_NAMES
+        .iter()
+        .find(|(code, language)| *language == name || *code == name)
+        .map(|(code, _)| *code)
+}
+
+/// Average probability of the tokens of the segments, None without segments
+fn whisper_confidence(segments: &[WhisperSegment]) -> Option<f32> {
+    if segments.is_empty() {
+        return None;
+    }
+    let total: f32 = segments.iter().map(|s| s.avg_logprob.exp()).sum();
+    Some(total / segments.len() as f32)
+}
+
 #[async_trait]
 impl SpeechToText for SpeechToTextMock {
-    async fn transcribe(&self, _stream: AudioStream, language: &str) -> anyhow::Result<String> {
+    async fn transcribe(
+        &self,
+        _stream: AudioStream,
+        _extension: &str,
+        language: &str,
+        _model: Option<&str>,
+    ) -> Result<Transcription, TranscriptionError> {
         tracing::info!("transcribe with language {}", language);
-        Ok("hello".to_string())
+        Ok(Transcription {
+            text: "hello".to_string(),
+            confidence: None,
+            detected_language: None,
+        })
+    }
+
+    fn supports_language(&self, _language: &str) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remove_filler(transcription: &str, language: &str) -> String {
+        let words = [("en", "um"), ("en", "uh"), ("es", "eh")]
+            .into_iter()
+            .map(|(language, word)| (language.to_string(), word.to_string()))
+            .collect();
+        RemoveFiller::new(words)
+            .process(transcription.to_string(), language)
+            .unwrap()
+    }
+
+    #[test]
+    fn transcription_error_source_is_the_wrapped_error() {
+        let err = TranscriptionError::from(anyhow::anyhow!("connection reset"));
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "connection reset");
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(err)),
+            "failed to transcribe audio: connection reset"
+        );
+    }
+
+    #[test]
+    fn removes_filler_words_of_the_language() {
+        assert_eq!(remove_filler("I um think uh so", "en"), "I think so");
+        assert_eq!(remove_filler("eh no sé", "es"), "no sé");
+        assert_eq!(remove_filler("I um think", "es"), "I um think");
+        assert_eq!(remove_filler("um, é um livro", "pt"), "um, é um livro");
+    }
+
+    #[test]
+    fn removes_filler_words_ignoring_case_and_punctuation() {
+        assert_eq!(
+            remove_filler("Um, I think. Uh... yes", "en"),
+            "I think. yes"
+        );
+        assert_eq!(remove_filler("I think, um.", "en"), "I think,");
+        assert_eq!(remove_filler("umbrella, hum", "en"), "umbrella, hum");
+    }
+
+    #[test]
+    fn keeps_whitespace_and_line_breaks() {
+        assert_eq!(remove_filler("I  think\n\nso", "en"), "I  think\n\nso");
+        assert_eq!(remove_filler("first um\nsecond", "en"), "first\nsecond");
+        assert_eq!(remove_filler("first\num second", "en"), "first\nsecond");
+        assert_eq!(remove_filler("first um\n", "en"), "first\n");
+        assert_eq!(remove_filler("um uh", "en"), "");
     }
 }
@@ -0,0 +1,88 @@
+//! Webhook deliveries are signed with HMAC-SHA256 using the webhook secret,
+//! the hex encoded signature is sent in the [`SIGNATURE_HEADER`] header,
+//! optionally prefixed with `sha256=`.
+//!
+//! A recipient verifies a delivery by computing the HMAC of the raw request
+//! body, before parsing it, and comparing it in constant time with the header:
+//!
+//! ```ignore
+//! let signature = headers.get("x-audionotes-signature")?.to_str()?;
+//! if !verify_webhook_signature(&body, signature, secret.as_bytes()) {
+//!     return StatusCode::UNAUTHORIZED;
+//! }
+//! ```
+//!
+//! Outside of Rust the same check is, for example in Python:
+//!
+//! ```text
+//! expected = hmac.new(secret, body, hashlib.sha256).hexdigest()
+//! hmac.compare_digest(expected, signature.removeprefix("sha256="))
+//! ```
+
+use data_encoding::HEXLOWER_PERMISSIVE;
+use ring::hmac;
+
+#[allow(dead_code)]
+pub const SIGNATURE_HEADER: &str = "x-audionotes-signature";
+
+/// Whether `signature` is the HMAC-SHA256 of `payload` with `secret`
+#[allow(dead_code)]
+pub fn verify_webhook_signature(payload: &[u8], signature: &str, secret: &[u8]) -> bool {
+    let signature = signature.trim();
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let Ok(signature) = HEXLOWER_PERMISSIVE.decode(signature.as_bytes()) else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::verify(&key, payload, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+    const PAYLOAD: &[u8] = br#"{"audio_id":1}"#;
+    const SIGNATURE: &str = "191ca65fa61c41a399c85ce803521ec6ba3ab6f3b95c65239823febc2775b25f";
+
+    #[test]
+    fn accepts_valid_signature() {
+        assert!(verify_webhook_signature(PAYLOAD, SIGNATURE, SECRET));
+        assert!(verify_webhook_signature(
+            PAYLOAD,
+            &format!("sha256={SIGNATURE}"),
+            SECRET
+        ));
+        assert!(verify_webhook_signature(
+            PAYLOAD,
+            &SIGNATURE.to_uppercase(),
+            SECRET
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        assert!(!verify_webhook_signature(PAYLOAD, SIGNATURE, b"other"));
+    }
+
+    #[test]
+    fn rejects_modified_payload() {
+        assert!(!verify_webhook_signature(
+            br#"{"audio_id":2}"#,
+            SIGNATURE,
+            SECRET
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_signature() {
+        assert!(!verify_webhook_signature(PAYLOAD, "", SECRET));
+        assert!(!verify_webhook_signature(PAYLOAD, "not hex", SECRET));
+        assert!(!verify_webhook_signature(
+            PAYLOAD,
+            &SIGNATURE[..SIGNATURE.len() - 2],
+            SECRET
+        ));
+    }
+}